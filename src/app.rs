@@ -1,16 +1,42 @@
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use image::ImageFormat;
 
 use eframe::egui::{self, Color32, Key};
 
-use std::net::Ipv4Addr;
-
+use std::net::IpAddr;
+
+use crate::connection::discovery::{CasterBrowser, DEFAULT_DISCOVERY_PORT};
+use crate::connection::message::MediaTransport;
+use crate::connection::tls::{TlsClientConfig, TlsIdentity};
+use crate::connection::viewer::ViewerConfig;
+use crate::connection::{host_port, user_name, DEFAULT_MEDIA_PORT, DEFAULT_SIGNALING_PORT};
+use crate::streaming::audio::{AudioConfig, AudioSource};
+use crate::streaming::client::{ConnectionState, ReconnectPolicy, StreamingClientConfig};
+use crate::streaming::relay::{RelayConfig, DEFAULT_RELAY_PORT};
+use crate::streaming::hls::HlsConfig;
+use crate::streaming::rtmp::RtmpConfig;
+use crate::streaming::rtsp::RtspConfig;
+use crate::streaming::server::{MulticastConfig, StreamingServerConfig};
+use crate::streaming::srt::SrtConfig;
 use crate::streaming::Streaming;
 use winit::event_loop::EventLoop;
 
-fn is_valid_ipv4(ip: &str) -> bool {
-    ip.parse::<Ipv4Addr>().is_ok()
+/// IPv4 or IPv6 literal (optionally in brackets) or host name, resolved later by the system resolver
+fn is_valid_address(address: &str) -> bool {
+    let address = address.trim_start_matches('[').trim_end_matches(']');
+    address.parse::<IpAddr>().is_ok()
+        || (!address.is_empty()
+            && address.len() <= 253
+            && address.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            }))
 }
 
 #[derive(Clone, Copy, PartialEq)]  // Aggiunto PartialEq per l'enum Mode
@@ -38,6 +64,61 @@ impl Default for TransmissionStatus {
     }
 }
 
+/// Samples shown in the statistics graphs, one per second
+const STATS_SAMPLES: usize = 60;
+
+/// Recent values of the statistics, for the graphs
+#[derive(Default)]
+struct StatsHistory {
+    last_sample: Option<Instant>,
+    fps: VecDeque<f32>,
+    bitrate: VecDeque<f32>,
+    jitter: VecDeque<f32>,
+}
+
+impl StatsHistory {
+    fn push(&mut self, fps: f32, bitrate: f32, jitter: f32) {
+        if self.last_sample.is_some_and(|last| last.elapsed().as_secs_f32() < 1.0) {
+            return;
+        }
+        self.last_sample = Some(Instant::now());
+        for (series, value) in [(&mut self.fps, fps), (&mut self.bitrate, bitrate), (&mut self.jitter, jitter)] {
+            if series.len() == STATS_SAMPLES {
+                series.pop_front();
+            }
+            series.push_back(value);
+        }
+    }
+}
+
+fn audio_source_name(source: AudioSource) -> &'static str {
+    match source {
+        AudioSource::Microphone => "Microphone",
+        AudioSource::SystemOutput => "System output",
+        AudioSource::Both => "Microphone and system output",
+        AudioSource::Test => "Test tone",
+    }
+}
+
+/// Small line graph of the recent samples, scaled to their maximum, followed by the label
+fn sparkline(ui: &mut egui::Ui, values: &VecDeque<f32>, label: String) {
+    ui.horizontal(|ui| {
+        let (rect, _) = ui.allocate_exact_size(egui::vec2(120.0, 24.0), egui::Sense::hover());
+        ui.painter().rect_filled(rect, 2.0, Color32::from_gray(30));
+        let max = values.iter().copied().fold(f32::EPSILON, f32::max);
+        let points: Vec<egui::Pos2> = values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| egui::pos2(
+                rect.left() + rect.width() * i as f32 / (STATS_SAMPLES - 1) as f32,
+                rect.bottom() - rect.height() * value / max,
+            ))
+            .collect();
+        ui.painter().add(egui::Shape::line(points, egui::Stroke::new(1.5, Color32::LIGHT_GREEN)));
+        ui.label(label);
+    });
+}
+
 #[derive(PartialEq, Clone)]
 struct ScreenArea {
     startx: u32,
@@ -52,6 +133,28 @@ pub struct MyApp {
     texture: Option<egui::TextureHandle>,
     mode: Mode,
     caster_address: String,
+    caster_name: String,
+    caster_browser: Option<CasterBrowser>,
+    signaling_port: u16,
+    media_port: u16,
+    passphrase: String,
+    require_approval: bool,
+    reconnect: bool,
+    relay: bool,
+    relay_port: u16,
+    tls: bool,
+    srtp: bool,
+    multicast: bool,
+    rtsp: bool,
+    hls: bool,
+    viewer: bool,
+    srt: bool,
+    rtmp: bool,
+    rtmp_url: String,
+    rtmp_key: String,
+    audio: bool,
+    audio_source: AudioSource,
+    play_audio: bool,
     selected_screen_area: Option<ScreenArea>,
     transmission_status: TransmissionStatus,
     pause: bool,
@@ -63,6 +166,7 @@ pub struct MyApp {
     slider_value4: f32,
     screen_width: u32,
     screen_height: u32,
+    stats_history: StatsHistory,
 
 }
 
@@ -94,6 +198,28 @@ impl MyApp {
             texture: None,
             mode: Mode::default(),
             caster_address: String::default(),
+            caster_name: user_name(),
+            caster_browser: None,
+            signaling_port: DEFAULT_SIGNALING_PORT,
+            media_port: DEFAULT_MEDIA_PORT,
+            passphrase: String::default(),
+            require_approval: false,
+            reconnect: false,
+            relay: false,
+            relay_port: DEFAULT_RELAY_PORT,
+            tls: false,
+            srtp: true,
+            multicast: false,
+            rtsp: false,
+            hls: false,
+            viewer: false,
+            srt: false,
+            rtmp: false,
+            rtmp_url: RtmpConfig::default().url,
+            rtmp_key: RtmpConfig::default().stream_key,
+            audio: false,
+            audio_source: AudioConfig::default().source,
+            play_audio: true,
             selected_screen_area: None,
             transmission_status: TransmissionStatus::default(),
             pause: false,
@@ -105,7 +231,81 @@ impl MyApp {
             slider_value4: 0.0,
             screen_width: screen_width,
             screen_height: screen_height,
+            stats_history: StatsHistory::default(),
+
+        }
+    }
+
+    /// Collapsible statistics of the current stream, with graphs of the last minute
+    fn stats_panel(&mut self, ui: &mut egui::Ui) {
+        let history = &mut self.stats_history;
+        match &self._streaming {
+            Some(Streaming::Server(s)) => {
+                let stats = s.stats();
+                let jitter = stats.peers.iter().filter_map(|peer| peer.jitter_ms).fold(0.0, f32::max);
+                history.push(stats.encoded_fps, stats.bitrate as f32, jitter);
+                egui::CollapsingHeader::new("Statistics").show(ui, |ui| {
+                    if let Some((width, height)) = stats.resolution {
+                        ui.label(format!("Resolution: {}x{}", width, height));
+                    }
+                    sparkline(ui, &history.fps, format!("{:.1} fps encoded", stats.encoded_fps));
+                    sparkline(ui, &history.bitrate, format!("{} kbit/s (target {} kbit/s)", stats.bitrate, stats.target_bitrate));
+                    sparkline(ui, &history.jitter, format!("{:.1} ms worst jitter", jitter));
+                    ui.label(format!("Packets sent: {}", stats.packets_sent));
+                    for peer in &stats.peers {
+                        let mut line = format!("{} ({}) over {:?}", peer.name, peer.ip, peer.transport);
+                        if let Some(loss) = peer.fraction_lost {
+                            line.push_str(&format!(", {:.1}% loss", loss * 100.0));
+                        }
+                        if let Some(jitter) = peer.jitter_ms {
+                            line.push_str(&format!(", {:.1} ms jitter", jitter));
+                        }
+                        if let Some(round_trip) = peer.round_trip {
+                            line.push_str(&format!(", {} ms round trip", round_trip.as_millis()));
+                        }
+                        if let Some(latency) = peer.latency {
+                            line.push_str(&format!(", ~{} ms latency", latency.as_millis()));
+                        }
+                        ui.label(line);
+                    }
+                });
+            }
+            Some(Streaming::Client(s)) => {
+                let stats = s.stats();
+                history.push(stats.decoded_fps, stats.bitrate as f32, stats.jitter_ms);
+                egui::CollapsingHeader::new("Statistics").show(ui, |ui| {
+                    if let Some((width, height)) = stats.resolution {
+                        ui.label(format!("Resolution: {}x{}", width, height));
+                    }
+                    sparkline(ui, &history.fps, format!("{:.1} fps decoded", stats.decoded_fps));
+                    sparkline(ui, &history.bitrate, format!("{} kbit/s received", stats.bitrate));
+                    sparkline(ui, &history.jitter, format!("{:.1} ms jitter", stats.jitter_ms));
+                    ui.label(format!(
+                        "Packets received: {}, lost: {} ({:.2}%)",
+                        stats.packets_received,
+                        stats.packets_lost,
+                        stats.fraction_lost * 100.0
+                    ));
+                    if let Some(latency) = stats.latency {
+                        ui.label(format!("Pipeline latency: {} ms", latency.as_millis()));
+                    }
+                    if let Some(latency) = stats.glass_to_glass {
+                        ui.label(format!("Glass-to-glass latency: {} ms", latency.as_millis()));
+                    }
+                    if let Some(offset) = s.clock_offset() {
+                        ui.label(format!("Caster clock offset: {:+.1} ms (round trip {} ms)", offset.offset_us as f64 / 1000.0, offset.round_trip.as_millis()));
+                    }
+                });
+            }
+            None => {}
+        }
+    }
 
+    fn passphrase(&self) -> Option<String> {
+        if self.passphrase.is_empty() {
+            None
+        } else {
+            Some(self.passphrase.clone())
         }
     }
 }
@@ -144,6 +344,64 @@ impl eframe::App for MyApp {
 
             match self.mode {
                 Mode::Caster => {
+                    ui.horizontal(|ui| {
+                        ui.label("Port:");
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::DragValue::new(&mut self.signaling_port));
+                        ui.label("Passphrase (optional):");
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::TextEdit::singleline(&mut self.passphrase).password(true));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Name shown to receivers:");
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::TextEdit::singleline(&mut self.caster_name));
+                    });
+                    ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::Checkbox::new(&mut self.require_approval, "Ask before letting receivers in"));
+                    ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::Checkbox::new(&mut self.tls, "Encrypt signaling (TLS, self-signed certificate)"));
+                    ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::Checkbox::new(&mut self.srtp, "Encrypt video (SRTP)"));
+                    ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::Checkbox::new(&mut self.multicast, format!("Multicast to {} (one stream for all receivers)", MulticastConfig::default().group)));
+                    // the outputs for stock players can't check who is watching, they can still be unchecked
+                    let protected = !self.passphrase.is_empty() || self.require_approval;
+                    ui.horizontal(|ui| {
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle && (!protected || self.rtsp), egui::Checkbox::new(&mut self.rtsp, format!("Serve RTSP on port {} (VLC, ffplay...), unencrypted", RtspConfig::default().port)));
+                        if protected {
+                            ui.weak("not with a passphrase or approval, RTSP has no authentication");
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle && (!protected || self.hls), egui::Checkbox::new(&mut self.hls, format!("Serve HLS on port {} (browsers, higher latency), unencrypted", HlsConfig::default().port)));
+                        if protected {
+                            ui.weak("not with a passphrase or approval, HLS has no authentication");
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle && (!protected || self.viewer), egui::Checkbox::new(&mut self.viewer, format!("Serve a browser viewer on port {} (low frame rate, no install), unencrypted", ViewerConfig::default().port)));
+                        if protected {
+                            ui.weak("not with a passphrase or approval, browsers aren't admitted");
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        // it's encrypted with the caster's passphrase, when there is one
+                        let unprotected = self.require_approval && self.passphrase.is_empty();
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle && (!unprotected || self.srt), egui::Checkbox::new(&mut self.srt, format!("Serve SRT on port {} (listener, passphrases need 10+ characters)", SrtConfig::default().port)));
+                        if unprotected {
+                            ui.weak("not with approval alone, SRT callers are only checked by the passphrase");
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::Checkbox::new(&mut self.rtmp, "Push to RTMP server"));
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle && self.rtmp, egui::TextEdit::singleline(&mut self.rtmp_url).desired_width(180.0));
+                        ui.label("Stream key:");
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle && self.rtmp, egui::TextEdit::singleline(&mut self.rtmp_key).password(true).desired_width(100.0));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::Checkbox::new(&mut self.audio, "Send audio (Opus)"));
+                        ui.add_enabled_ui(self.transmission_status == TransmissionStatus::Idle && self.audio, |ui| {
+                            egui::ComboBox::from_id_source("audio_source").selected_text(audio_source_name(self.audio_source)).show_ui(ui, |ui| {
+                                for source in [AudioSource::Microphone, AudioSource::SystemOutput, AudioSource::Both, AudioSource::Test] {
+                                    ui.selectable_value(&mut self.audio_source, source, audio_source_name(source));
+                                }
+                            });
+                        });
+                    });
                     ui.label("Select screen area:");
                     ui.horizontal(|ui| {
                         if ui.selectable_value(&mut None, self.selected_screen_area.clone(), "Total screen").clicked(){
@@ -230,11 +488,74 @@ impl eframe::App for MyApp {
                     });
                 }
                 Mode::Receiver => {
-                    ui.label("Enter caster's address:");
+                    if self.transmission_status == TransmissionStatus::Idle {
+                        if self.caster_browser.is_none() {
+                            match CasterBrowser::new(DEFAULT_DISCOVERY_PORT) {
+                                Ok(browser) => self.caster_browser = Some(browser),
+                                Err(e) => println!("Discovery unavailable: {}", e),
+                            }
+                        }
+                        if let Some(browser) = &self.caster_browser {
+                            // beacons keep arriving, keep the list fresh
+                            ctx.request_repaint_after(std::time::Duration::from_secs(1));
+                            let casters = browser.casters();
+                            egui::CollapsingHeader::new(format!("Available casters ({})", casters.len())).default_open(true).show(ui, |ui| {
+                                if casters.is_empty() {
+                                    ui.label("No caster found on the local network");
+                                }
+                                for caster in casters {
+                                    let mut label = format!("{} ({})", caster.name, host_port(&caster.ip.to_string(), caster.port));
+                                    if caster.passphrase_required {
+                                        label.push_str(" - passphrase");
+                                    }
+                                    if caster.tls {
+                                        label.push_str(" - TLS");
+                                    }
+                                    if ui.selectable_label(self.caster_address == caster.ip.to_string() && self.signaling_port == caster.port, label).clicked() {
+                                        self.caster_address = caster.ip.to_string();
+                                        self.signaling_port = caster.port;
+                                        self.tls = caster.tls;
+                                    }
+                                }
+                            });
+                        }
+                    } else {
+                        // only needed to pick a caster
+                        self.caster_browser = None;
+                    }
+
+                    ui.label("Enter caster's address (IPv4, IPv6 or host name):");
 
                     ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, |ui: &mut egui::Ui|{
                         ui.text_edit_singleline(&mut self.caster_address)
                     });
+                    ui.horizontal(|ui| {
+                        ui.label("Caster port:");
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::DragValue::new(&mut self.signaling_port));
+                        ui.label("Local media port:");
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::DragValue::new(&mut self.media_port));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Passphrase:");
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::TextEdit::singleline(&mut self.passphrase).password(true));
+                    });
+                    ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::Checkbox::new(&mut self.reconnect, "Reconnect automatically"));
+                    ui.horizontal(|ui| {
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::Checkbox::new(&mut self.relay, "Relay the stream to other receivers on port"));
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle && self.relay, egui::DragValue::new(&mut self.relay_port));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::Checkbox::new(&mut self.tls, "Use TLS (the caster certificate is trusted on first use)"));
+                        // after the caster got a new certificate, its next one is pinned instead
+                        if ui.add_enabled(self.transmission_status == TransmissionStatus::Idle && self.tls && is_valid_address(self.caster_address.trim()), egui::Button::new("Forget pinned certificate")).clicked() {
+                            match TlsClientConfig::default().forget(self.caster_address.trim(), self.signaling_port) {
+                                Ok(_) => self.error_msg = None,
+                                Err(e) => self.error_msg = Some(e.to_string()),
+                            }
+                        }
+                    });
+                    ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::Checkbox::new(&mut self.srt, format!("Pull over SRT from port {} instead (no signaling)", SrtConfig::default().port)));
+                    ui.add_enabled(self.transmission_status == TransmissionStatus::Idle, egui::Checkbox::new(&mut self.play_audio, "Play the caster's audio"));
                 }
             }
 
@@ -258,9 +579,34 @@ impl eframe::App for MyApp {
                                                 let image = egui::ColorImage::from_rgba_premultiplied(size, &image);
                         
                                                 *image_clone.lock().unwrap() = Some(image);
+                                            }, StreamingServerConfig {
+                                                port: self.signaling_port,
+                                                name: self.caster_name.clone(),
+                                                passphrase: self.passphrase(),
+                                                require_approval: self.require_approval,
+                                                tls: self.tls.then(TlsIdentity::self_signed),
+                                                srtp: self.srtp,
+                                                multicast: self.multicast.then(MulticastConfig::default),
+                                                rtsp: self.rtsp.then(RtspConfig::default),
+                                                hls: self.hls.then(HlsConfig::default),
+                                                viewer: self.viewer.then(ViewerConfig::default),
+                                                srt: self.srt.then(|| SrtConfig {
+                                                    passphrase: self.passphrase(),
+                                                    ..Default::default()
+                                                }),
+                                                rtmp: self.rtmp.then(|| RtmpConfig {
+                                                    url: self.rtmp_url.trim().to_string(),
+                                                    stream_key: self.rtmp_key.trim().to_string(),
+                                                }),
+                                                audio: self.audio.then(|| AudioConfig {
+                                                    source: self.audio_source,
+                                                    ..Default::default()
+                                                }),
+                                                ..Default::default()
                                             }) {
                                                 Ok(s) => {
                                                     self._streaming = Some(s);
+                                                    self.stats_history = StatsHistory::default();
                                                 }
                                                 Err(e) => {
                                                     self.error_msg = Some(e.to_string());
@@ -282,9 +628,34 @@ impl eframe::App for MyApp {
                                         let image = egui::ColorImage::from_rgba_premultiplied(size, &image);
                 
                                         *image_clone.lock().unwrap() = Some(image);
+                                    }, StreamingServerConfig {
+                                        port: self.signaling_port,
+                                        name: self.caster_name.clone(),
+                                        passphrase: self.passphrase(),
+                                        require_approval: self.require_approval,
+                                        tls: self.tls.then(TlsIdentity::self_signed),
+                                        srtp: self.srtp,
+                                        multicast: self.multicast.then(MulticastConfig::default),
+                                        rtsp: self.rtsp.then(RtspConfig::default),
+                                        hls: self.hls.then(HlsConfig::default),
+                                        viewer: self.viewer.then(ViewerConfig::default),
+                                        srt: self.srt.then(|| SrtConfig {
+                                            passphrase: self.passphrase(),
+                                            ..Default::default()
+                                        }),
+                                        rtmp: self.rtmp.then(|| RtmpConfig {
+                                            url: self.rtmp_url.trim().to_string(),
+                                            stream_key: self.rtmp_key.trim().to_string(),
+                                        }),
+                                        audio: self.audio.then(|| AudioConfig {
+                                            source: self.audio_source,
+                                            ..Default::default()
+                                        }),
+                                        ..Default::default()
                                     }) {
                                         Ok(s) => {
                                             self._streaming = Some(s);
+                                            self.stats_history = StatsHistory::default();
                                         }
                                         Err(e) => {
                                             self.error_msg = Some(e.to_string());
@@ -310,9 +681,9 @@ impl eframe::App for MyApp {
                         Mode::Receiver => {
                             ui.horizontal(|ui| {
                                 if ui.button("Start reception without recording").clicked() {
-                                    if is_valid_ipv4(&self.caster_address){
+                                    if is_valid_address(self.caster_address.trim()){
                                         let image_clone = self.current_image.clone();
-                                        match Streaming::new_client(&self.caster_address, move |bytes| {
+                                        match Streaming::new_client(self.caster_address.trim(), move |bytes| {
                                             let image = image::load_from_memory_with_format(bytes, ImageFormat::Jpeg)
                                                 .unwrap()
                                                 .to_rgba8();
@@ -321,9 +692,26 @@ impl eframe::App for MyApp {
                                             let image = egui::ColorImage::from_rgba_premultiplied(size, &image);
                     
                                             *image_clone.lock().unwrap() = Some(image);
-                                        }, false) {
+                                        }, false, StreamingClientConfig {
+                                            port: self.signaling_port,
+                                            media_port: self.media_port,
+                                            passphrase: self.passphrase(),
+                                            reconnect: self.reconnect.then(ReconnectPolicy::default),
+                                            tls: self.tls.then(TlsClientConfig::default),
+                                            relay: self.relay.then(|| RelayConfig {
+                                                port: self.relay_port,
+                                                ..Default::default()
+                                            }),
+                                            srt: self.srt.then(|| SrtConfig {
+                                                passphrase: self.passphrase(),
+                                                ..SrtConfig::caller(self.caster_address.trim())
+                                            }),
+                                            audio: self.play_audio,
+                                            ..Default::default()
+                                        }) {
                                             Ok(s) => {
                                                 self._streaming = Some(s);
+                                                self.stats_history = StatsHistory::default();
                                             }
                                             Err(e) => {
                                                 self.error_msg = Some(e.to_string());
@@ -342,13 +730,13 @@ impl eframe::App for MyApp {
                                         }
                                     }
                                     else{
-                                        self.error_msg = Some("Please insert a valid IP address!".to_string());
+                                        self.error_msg = Some("Please insert a valid IP address or host name!".to_string());
                                     }
                                 }
                                 if ui.button("Start reception and save recording").clicked() {
-                                    if is_valid_ipv4(&self.caster_address){
+                                    if is_valid_address(self.caster_address.trim()){
                                         let image_clone = self.current_image.clone();
-                                        match Streaming::new_client(&self.caster_address, move |bytes| {
+                                        match Streaming::new_client(self.caster_address.trim(), move |bytes| {
                                             let image = image::load_from_memory_with_format(bytes, ImageFormat::Jpeg)
                                                 .unwrap()
                                                 .to_rgba8();
@@ -357,9 +745,26 @@ impl eframe::App for MyApp {
                                             let image = egui::ColorImage::from_rgba_premultiplied(size, &image);
                     
                                             *image_clone.lock().unwrap() = Some(image);
-                                        },true) {
+                                        }, true, StreamingClientConfig {
+                                            port: self.signaling_port,
+                                            media_port: self.media_port,
+                                            passphrase: self.passphrase(),
+                                            reconnect: self.reconnect.then(ReconnectPolicy::default),
+                                            tls: self.tls.then(TlsClientConfig::default),
+                                            relay: self.relay.then(|| RelayConfig {
+                                                port: self.relay_port,
+                                                ..Default::default()
+                                            }),
+                                            srt: self.srt.then(|| SrtConfig {
+                                                passphrase: self.passphrase(),
+                                                ..SrtConfig::caller(self.caster_address.trim())
+                                            }),
+                                            audio: self.play_audio,
+                                            ..Default::default()
+                                        }) {
                                             Ok(s) => {
                                                 self._streaming = Some(s);
+                                                self.stats_history = StatsHistory::default();
                                             }
                                             Err(e) => {
                                                 self.error_msg = Some(e.to_string());
@@ -378,7 +783,7 @@ impl eframe::App for MyApp {
                                         }
                                     }
                                     else{
-                                        self.error_msg = Some("Please insert a valid IP address!".to_string());
+                                        self.error_msg = Some("Please insert a valid IP address or host name!".to_string());
                                     }
                                 }
                             });
@@ -440,21 +845,122 @@ impl eframe::App for MyApp {
                         }
                     });
 
+                    if let Some(Streaming::Server(s)) = &self._streaming {
+                        // receivers can show up at any time, keep polling for them
+                        ctx.request_repaint_after(std::time::Duration::from_millis(250));
+                        for request in s.pending_peers() {
+                            ui.horizontal(|ui| {
+                                ui.colored_label(egui::Color32::YELLOW, format!("{} ({}) wants to watch", request.name, request.ip));
+                                if ui.button("Allow").clicked() {
+                                    s.allow(&request);
+                                }
+                                if ui.button("Deny").clicked() {
+                                    s.deny(&request);
+                                }
+                            });
+                        }
+
+                        if let Some(url) = s.rtsp_url() {
+                            ui.label(format!("RTSP: {}", url));
+                        }
+                        if let Some(url) = s.hls_url() {
+                            ui.label(format!("HLS: {}", url));
+                        }
+                        if let Some(url) = s.srt_url() {
+                            ui.label(format!("SRT: {}", url));
+                        }
+                        if let Some(url) = s.rtmp_url() {
+                            ui.label(format!("RTMP: pushing to {}", url));
+                        }
+                        if let Some(url) = s.viewer_url() {
+                            ui.label(format!("Browser viewer: {} ({} watching)", url, s.viewers()));
+                        }
+                        if let Some(fingerprint) = s.fingerprint() {
+                            ui.label(format!("Certificate fingerprint: {}", fingerprint));
+                        }
+
+                        let changes = s.bitrate_changes();
+                        egui::CollapsingHeader::new(format!("Bitrate: {} kbit/s", s.bitrate())).show(ui, |ui| {
+                            for change in changes.iter().rev() {
+                                ui.label(format!("{} {} kbit/s: {}", change.at.format("%H:%M:%S"), change.bitrate, change.reason));
+                            }
+                        });
+
+                        let peers = s.peers();
+                        egui::CollapsingHeader::new(format!("Receivers ({})", peers.len())).show(ui, |ui| {
+                            if peers.is_empty() {
+                                ui.label("Nobody is watching");
+                            }
+                            for peer in peers {
+                                ui.horizontal(|ui| {
+                                    ui.label(format!("{} ({}) since {}", peer.name, peer.ip, peer.connected_at.format("%H:%M:%S")));
+                                    if let Some(reception) = peer.reception {
+                                        ui.label(format!("{:.1}% loss, {:.0} ms jitter", reception.fraction_lost * 100.0, reception.jitter_ms));
+                                    }
+                                    if ui.button("Kick").clicked() {
+                                        s.kick(&peer);
+                                    }
+                                    if ui.button("Ban").clicked() {
+                                        s.ban(peer.ip);
+                                    }
+                                });
+                            }
+                        });
+                    }
+                    self.stats_panel(ui);
+
                 }
                 TransmissionStatus::Receiving => {
-                    ui.label(format!("Receiving..."));
+                    // keep checking the connection even when nothing else triggers a repaint
+                    ctx.request_repaint_after(std::time::Duration::from_millis(250));
+                    match &self._streaming {
+                        Some(Streaming::Client(s)) => match s.connection_state() {
+                            ConnectionState::Reconnecting { attempt } => {
+                                ui.colored_label(egui::Color32::YELLOW, format!("Reconnecting... (attempt {})", attempt));
+                            }
+                            _ if s.is_awaiting_approval() => {
+                                ui.colored_label(egui::Color32::YELLOW, "Waiting for the caster's approval...");
+                            }
+                            _ if s.is_caster_paused() => {
+                                ui.colored_label(egui::Color32::LIGHT_RED, "Caster paused...");
+                            }
+                            _ if s.is_caster_blanked() => {
+                                ui.colored_label(egui::Color32::LIGHT_RED, "Caster blanked the screen...");
+                            }
+                            _ if s.media_transport() == MediaTransport::Websocket => {
+                                ui.label("Receiving... (over the websocket, UDP seems blocked)");
+                            }
+                            _ => {
+                                ui.label("Receiving...");
+                            }
+                        },
+                        _ => {
+                            ui.label("Receiving...");
+                        }
+                    }
+                    if let Some(Streaming::Client(s)) = &self._streaming {
+                        if let Some(receivers) = s.relay_receivers() {
+                            ui.label(format!("Relaying to {} receiver(s) on port {}", receivers, self.relay_port));
+                        } else if s.is_relay_refused() {
+                            ui.colored_label(egui::Color32::YELLOW, "Not relaying: the caster approves every receiver, the relay couldn't");
+                        }
+                    }
+                    self.stats_panel(ui);
                     if ui.button("Stop reception").clicked() {
+                        // the address is kept to start again without retyping it
                         self._streaming.take();
-                        self.caster_address = String::default();
                         self.current_image = Arc::new(Mutex::new(Some(egui::ColorImage::new(
                             [200, 200],
                             Color32::BLACK))));
                         self.transmission_status = TransmissionStatus::Idle;
                     }
                     if let Some(Streaming::Client(s)) = &self._streaming {
-                        if !s.is_connected() {
+                        let reconnecting = matches!(s.connection_state(), ConnectionState::Reconnecting { .. });
+                        if !reconnecting && !s.is_connected() {
+                            if let Some(e) = s.take_error() {
+                                self.error_msg = Some(e.to_string());
+                            }
                             self._streaming.take();
-                            self.caster_address = String::default();
                             self.current_image = Arc::new(Mutex::new(Some(egui::ColorImage::new(
                                 [200, 200],
                                 Color32::BLACK))));
@@ -471,7 +977,19 @@ impl eframe::App for MyApp {
             drop(data);
 
             if let Some(texture) = &self.texture {
-                ui.add(egui::Image::from_texture(texture).shrink_to_fit());
+                let image = ui.add(egui::Image::from_texture(texture).shrink_to_fit());
+                // glass-to-glass latency over the received video
+                if let Some(Streaming::Client(s)) = &self._streaming {
+                    let text = match s.latency() {
+                        Some(latency) => format!("Latency: {} ms", latency.as_millis()),
+                        None => "Latency: measuring...".to_string(),
+                    };
+                    let painter = ui.painter_at(image.rect);
+                    let galley = painter.layout_no_wrap(text, egui::FontId::proportional(16.0), Color32::WHITE);
+                    let position = image.rect.left_top() + egui::vec2(8.0, 8.0);
+                    painter.rect_filled(egui::Rect::from_min_size(position, galley.size()).expand(4.0), 4.0, Color32::from_black_alpha(160));
+                    painter.galley(position, galley, Color32::WHITE);
+                }
             }
         });
     }
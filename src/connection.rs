@@ -1,2 +1,104 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
 pub mod client;
+pub mod clock;
+pub mod discovery;
+pub mod http;
+pub mod message;
 pub mod server;
+pub mod tls;
+pub mod viewer;
+
+// Default ports, chosen so that a caster and receivers can share a host:
+//
+// | port      | used by                                                     |
+// |-----------|-------------------------------------------------------------|
+// | 9000      | signaling websocket of the caster                           |
+// | 9001-9004 | receiver: video RTP and RTCP, audio RTP and RTCP            |
+// | 9005-9006 | caster: RTCP of the video and of the audio                  |
+// | 9010      | signaling websocket of a relay                              |
+// | 9011-9014 | multicast group: video RTP and RTCP, audio RTP and RTCP     |
+// | 9015      | RTCP of a relay                                             |
+// | 9020      | SRT output                                                  |
+// | 9030      | discovery beacons                                           |
+// | 8554      | RTSP output                                                 |
+// | 8080-8081 | HLS output and browser viewer                               |
+//
+// A second receiver or a relay's receiver on the same host needs a media port
+// outside of these ranges, e.g. 9101.
+pub const DEFAULT_SIGNALING_PORT: u16 = 9000;
+pub const DEFAULT_MEDIA_PORT: u16 = 9001;
+/// Port the caster receives the RTCP reports on
+pub const DEFAULT_RTCP_PORT: u16 = 9005;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Internal events of the signaling nodes
+enum Signal {
+    Heartbeat,
+    /// Time to measure the round trip and the clock offset again
+    Ping,
+}
+
+/// Name shown to the other side when none is configured
+pub fn user_name() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "rust-streamer".to_string())
+}
+
+/// Resolves a host name or an IPv4/IPv6 literal, optionally in brackets, through the system resolver
+pub fn resolve(host: &str, port: u16) -> io::Result<SocketAddr> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    (host, port).to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no address found for {}", host),
+        )
+    })
+}
+
+/// `host:port`, with brackets around IPv6 literals
+pub fn host_port(host: &str, port: u16) -> String {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Address of this host on the interface of the default route, to be shown in URLs.
+/// Connecting a UDP socket only selects the route, nothing is sent.
+pub fn local_ip() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9)).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+/// Wildcard addresses a UDP receiver has to bind on `port` to get the packets of
+/// both families, found like `listen_dual_stack` does: only `::` where it's
+/// dual-stack, `::` and `0.0.0.0` where it isn't (Windows), only `0.0.0.0`
+/// where IPv6 is disabled. The sockets are closed before returning.
+pub(crate) fn udp_wildcards(port: u16) -> io::Result<Vec<IpAddr>> {
+    listen_dual_stack(port, UdpSocket::bind)?
+        .iter()
+        .map(|socket| socket.local_addr().map(|addr| addr.ip()))
+        .collect()
+}
+
+/// Listens on `port` for both IPv6 and IPv4. Where IPv6 sockets are dual-stack
+/// the IPv4 bind fails and the IPv6 one is enough, where IPv6 is disabled only
+/// the IPv4 one succeeds.
+fn listen_dual_stack<T>(
+    port: u16,
+    mut listen: impl FnMut(SocketAddr) -> io::Result<T>,
+) -> io::Result<Vec<T>> {
+    let v6 = listen(([0u16; 8], port).into());
+    let v4 = listen(([0u8; 4], port).into());
+    match (v6, v4) {
+        (Err(_), Err(e)) => Err(e),
+        (v6, v4) => Ok(v6.into_iter().chain(v4).collect()),
+    }
+}
@@ -1,55 +1,207 @@
-use message_io::network::{NetEvent, Transport};
-use message_io::node::{self, NodeHandler};
+use super::clock::{now_micros, ClockOffset, ClockSync, PING_INTERVAL};
+use super::message::{auth_digest, ControlMessage, Hello};
+use super::tls::{self, TlsClientConfig};
+use super::{host_port, resolve, Signal, DEFAULT_SIGNALING_PORT, DEFAULT_TIMEOUT};
+use message_io::network::{Endpoint, NetEvent, Transport};
+use message_io::node::{self, NodeEvent, NodeHandler};
 use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{io, thread};
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectReason {
+    /// The caster said goodbye before closing the connection
+    Goodbye,
+    /// The connection was closed without a goodbye
+    Closed,
+    /// Nothing was received from the caster within the timeout
+    Timeout,
+}
+
+/// Cloneable handle used to talk to the caster, also given to the callbacks
+#[derive(Clone)]
+pub struct ClientHandle {
+    ws_handler: NodeHandler<Signal>,
+    endpoint: Endpoint,
+}
+
+impl ClientHandle {
+    pub fn send(&self, message: &ControlMessage) {
+        self.ws_handler
+            .network()
+            .send(self.endpoint, &message.encode());
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectionClientConfig {
+    pub port: u16,
+    /// Used to answer the caster's challenge, if it sends one
+    pub passphrase: Option<String>,
+    /// Heartbeats are sent a few times per timeout, the caster is considered
+    /// gone if it doesn't send anything for longer than that
+    pub timeout: Duration,
+    /// When set the caster is reached through TLS (wss) and its certificate is pinned
+    pub tls: Option<TlsClientConfig>,
+}
+
+impl Default for ConnectionClientConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_SIGNALING_PORT,
+            passphrase: None,
+            timeout: DEFAULT_TIMEOUT,
+            tls: None,
+        }
+    }
+}
+
 pub struct ConnectionClient {
-    ws_handler: NodeHandler<()>,
+    handle: ClientHandle,
+    clock: Arc<Mutex<ClockSync>>,
 }
 
 impl ConnectionClient {
     pub fn new<T: AsRef<str>>(
         ip: T,
-        mut on_disconnect: impl FnMut() -> () + Send + 'static,
+        hello: Hello,
+        config: ConnectionClientConfig,
+        mut on_message: impl FnMut(&ClientHandle, ControlMessage) + Send + 'static,
+        mut on_disconnect: impl FnMut(DisconnectReason) + Send + 'static,
     ) -> io::Result<Self> {
-        let (ws_handler, listener) = node::split::<()>();
+        let ConnectionClientConfig {
+            port,
+            passphrase,
+            timeout,
+            tls,
+        } = config;
 
-        ws_handler
-            .network()
-            .connect(Transport::Ws, format!("{}:9000", ip.as_ref()))?;
+        let address = resolve(ip.as_ref(), port)?;
+        // with TLS the websocket goes through a local proxy
+        let address = match tls {
+            Some(tls) => tls::connect(address, &host_port(ip.as_ref(), port), &tls)?,
+            None => address,
+        };
+
+        let (ws_handler, listener) = node::split::<Signal>();
+
+        let (endpoint, _) = ws_handler.network().connect(Transport::Ws, address)?;
+
+        let handle = ClientHandle {
+            ws_handler,
+            endpoint,
+        };
 
         let (tx, rx) = channel();
+        let clock = Arc::new(Mutex::new(ClockSync::default()));
+        let clock_clone = clock.clone();
 
+        let handle_clone = handle.clone();
         thread::spawn(move || {
-            listener.for_each(move |event| match event.network() {
-                NetEvent::Connected(_, success) => {
-                    tx.send(success).unwrap();
-                    if success {
-                        println!("Connected");
+            let handle = handle_clone;
+            // the caster may say goodbye before closing the socket, notify only once
+            let mut disconnected = false;
+            let mut notify_disconnect = move |reason| {
+                if !disconnected {
+                    disconnected = true;
+                    on_disconnect(reason);
+                }
+            };
+            let mut last_received = Instant::now();
+            listener.for_each(move |event| match event {
+                NodeEvent::Network(event) => match event {
+                    NetEvent::Connected(_, success) => {
+                        tx.send(success).unwrap();
+                        if success {
+                            println!("Connected");
+                            last_received = Instant::now();
+                            handle
+                                .ws_handler
+                                .signals()
+                                .send_with_timer(Signal::Heartbeat, timeout / 3);
+                            handle.ws_handler.signals().send(Signal::Ping);
+                        } else {
+                            println!("Failed to connect");
+                        }
+                    }
+                    NetEvent::Accepted(..) => unreachable!(),
+                    NetEvent::Message(_, data) => {
+                        last_received = Instant::now();
+                        match ControlMessage::decode(data) {
+                            Ok(ControlMessage::Goodbye) => {
+                                notify_disconnect(DisconnectReason::Goodbye)
+                            }
+                            Ok(ControlMessage::Heartbeat) => {}
+                            Ok(ControlMessage::Pong { sent_us, caster_us }) => clock_clone
+                                .lock()
+                                .unwrap()
+                                .add(sent_us, caster_us, now_micros()),
+                            Ok(ControlMessage::AuthChallenge { nonce }) => {
+                                let secret = passphrase.as_deref().unwrap_or_default();
+                                handle.send(&ControlMessage::AuthResponse {
+                                    digest: auth_digest(&nonce, secret),
+                                });
+                            }
+                            Ok(message) => on_message(&handle, message),
+                            Err(e) => println!("Invalid message from caster: {}", e),
+                        }
+                    }
+                    NetEvent::Disconnected(_) => notify_disconnect(DisconnectReason::Closed),
+                },
+                NodeEvent::Signal(Signal::Heartbeat) => {
+                    if last_received.elapsed() > timeout {
+                        println!("Caster timed out");
+                        handle
+                            .ws_handler
+                            .network()
+                            .remove(handle.endpoint.resource_id());
+                        notify_disconnect(DisconnectReason::Timeout);
                     } else {
-                        println!("Failed to connect");
+                        handle.send(&ControlMessage::Heartbeat);
+                        handle
+                            .ws_handler
+                            .signals()
+                            .send_with_timer(Signal::Heartbeat, timeout / 3);
                     }
                 }
-                NetEvent::Accepted(..) => unreachable!(),
-                NetEvent::Message(..) => println!("Message"),
-                NetEvent::Disconnected(_) => on_disconnect(),
+                NodeEvent::Signal(Signal::Ping) => {
+                    handle.send(&ControlMessage::Ping {
+                        sent_us: now_micros(),
+                    });
+                    handle
+                        .ws_handler
+                        .signals()
+                        .send_with_timer(Signal::Ping, PING_INTERVAL);
+                }
             });
         });
 
         if rx.recv().unwrap() {
-            Ok(Self { ws_handler })
+            handle.send(&ControlMessage::Hello(hello));
+            Ok(Self { handle, clock })
         } else {
-            ws_handler.stop();
+            handle.ws_handler.stop();
             Err(io::Error::new(
                 io::ErrorKind::ConnectionRefused,
                 "Failed to connect",
             ))
         }
     }
+
+    pub fn send(&self, message: &ControlMessage) {
+        self.handle.send(message);
+    }
+
+    /// Offset of the caster's clock, None until the first pong
+    pub fn clock_offset(&self) -> Option<ClockOffset> {
+        self.clock.lock().unwrap().estimate()
+    }
 }
 
 impl Drop for ConnectionClient {
     fn drop(&mut self) {
-        self.ws_handler.stop();
+        self.handle.send(&ControlMessage::Goodbye);
+        self.handle.ws_handler.stop();
     }
 }
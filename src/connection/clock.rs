@@ -0,0 +1,114 @@
+//! Offset between the caster's clock and the receiver's one, estimated from
+//! ping/pong round trips over the signaling websocket.
+//!
+//! The caster answers every `Ping` with its own time, assuming the pong takes
+//! half the round trip to come back the offset is the difference between that
+//! time and the receiver's one. The sample with the shortest round trip is the
+//! least affected by queuing and is the one used.
+
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const PING_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Round trips the estimate is chosen among
+const SAMPLES: usize = 8;
+
+/// Wall clock time in microseconds since the UNIX epoch
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockOffset {
+    /// Caster's clock minus the receiver's one, in microseconds
+    pub offset_us: i64,
+    /// Round trip of the ping the offset was estimated from
+    pub round_trip: Duration,
+}
+
+impl ClockOffset {
+    /// Converts a time of the receiver's clock to the caster's one
+    pub fn to_caster(&self, local_us: u64) -> u64 {
+        local_us.saturating_add_signed(self.offset_us)
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct ClockSync {
+    samples: VecDeque<ClockOffset>,
+}
+
+impl ClockSync {
+    /// Adds the sample of a pong, `sent_us` and `received_us` are times of the receiver's clock
+    pub fn add(&mut self, sent_us: u64, caster_us: u64, received_us: u64) {
+        let round_trip_us = received_us.saturating_sub(sent_us);
+        let offset_us = caster_us as i64 - (sent_us + round_trip_us / 2) as i64;
+        if self.samples.len() == SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(ClockOffset {
+            offset_us,
+            round_trip: Duration::from_micros(round_trip_us),
+        });
+    }
+
+    /// None until the first pong
+    pub fn estimate(&self) -> Option<ClockOffset> {
+        self.samples
+            .iter()
+            .min_by_key(|sample| sample.round_trip)
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_estimate_before_the_first_pong() {
+        assert_eq!(ClockSync::default().estimate(), None);
+    }
+
+    #[test]
+    fn offset_assumes_symmetric_paths() {
+        let mut sync = ClockSync::default();
+        // caster 5 s ahead, 10 ms each way
+        sync.add(1_000_000, 6_010_000, 1_020_000);
+        let estimate = sync.estimate().unwrap();
+        assert_eq!(estimate.offset_us, 5_000_000);
+        assert_eq!(estimate.round_trip, Duration::from_millis(20));
+        assert_eq!(estimate.to_caster(2_000_000), 7_000_000);
+    }
+
+    #[test]
+    fn shortest_round_trip_wins() {
+        let mut sync = ClockSync::default();
+        // caster 1 s behind, the first pong was queued on the way back
+        sync.add(10_000_000, 9_010_000, 10_500_000);
+        sync.add(20_000_000, 19_005_000, 20_010_000);
+        sync.add(30_000_000, 29_100_000, 30_200_000);
+        let estimate = sync.estimate().unwrap();
+        assert_eq!(estimate.offset_us, -1_000_000);
+        assert_eq!(estimate.round_trip, Duration::from_millis(10));
+        assert_eq!(estimate.to_caster(500_000), 0);
+    }
+
+    #[test]
+    fn old_samples_are_forgotten() {
+        let mut sync = ClockSync::default();
+        sync.add(0, 1_000, 0);
+        for i in 1..=SAMPLES as u64 {
+            sync.add(
+                i * 1_000_000,
+                i * 1_000_000 + 2_000_000,
+                i * 1_000_000 + 100_000,
+            );
+        }
+        assert_eq!(sync.estimate().unwrap().offset_us, 1_950_000);
+    }
+}
@@ -0,0 +1,200 @@
+//! Announcement of the casters on the local network.
+//!
+//! Every caster periodically sends a beacon to a multicast group and to the
+//! broadcast address, receivers listen on the discovery port and keep the
+//! casters heard recently.
+
+use super::message::PROTOCOL_VERSION;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Away from the UDP ports of the media, the receivers' RTCP one in particular
+/// would get the beacons
+pub const DEFAULT_DISCOVERY_PORT: u16 = 9030;
+
+const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 42, 99);
+
+const BEACON_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Casters not heard for this long are removed from the list
+const EXPIRATION: Duration = Duration::from_secs(4);
+
+/// Prefix of every beacon, datagrams without it are ignored
+const MAGIC: &[u8; 4] = b"RSTB";
+
+/// Content of the beacon sent by the casters
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Beacon {
+    pub version: u16,
+    pub name: String,
+    /// Port of the signaling websocket
+    pub port: u16,
+    pub passphrase_required: bool,
+    pub tls: bool,
+}
+
+impl Beacon {
+    fn encode(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        // serializing a plain struct into memory can't fail
+        data.extend(bincode::serialize(self).unwrap());
+        data
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        bincode::deserialize(data.strip_prefix(MAGIC)?).ok()
+    }
+}
+
+/// A caster heard on the local network
+#[derive(Debug, Clone, PartialEq)]
+pub struct CasterInfo {
+    pub ip: IpAddr,
+    pub port: u16,
+    pub name: String,
+    pub passphrase_required: bool,
+    pub tls: bool,
+}
+
+/// Sends the beacon until dropped
+pub struct Announcer {
+    running: Arc<AtomicBool>,
+}
+
+impl Announcer {
+    pub fn new(beacon: Beacon, discovery_port: u16) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_multicast_ttl_v4(1)?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let running_clone = running.clone();
+        let data = beacon.encode();
+        thread::spawn(move || {
+            while running_clone.load(Ordering::Relaxed) {
+                // either may be unreachable depending on the network, one is enough
+                for target in [MULTICAST_GROUP, Ipv4Addr::BROADCAST] {
+                    let _ = socket.send_to(&data, (target, discovery_port));
+                }
+                thread::sleep(BEACON_INTERVAL);
+            }
+        });
+
+        Ok(Self { running })
+    }
+}
+
+impl Drop for Announcer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Listens for beacons until dropped, several browsers can run on the same host
+pub struct CasterBrowser {
+    casters: Arc<Mutex<HashMap<SocketAddr, (CasterInfo, Instant)>>>,
+    running: Arc<AtomicBool>,
+}
+
+impl CasterBrowser {
+    pub fn new(discovery_port: u16) -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, discovery_port)).into())?;
+        let socket: UdpSocket = socket.into();
+        if let Err(e) = socket.join_multicast_v4(&MULTICAST_GROUP, &Ipv4Addr::UNSPECIFIED) {
+            println!("Discovery only through broadcast: {}", e);
+        }
+        // lets the thread notice when the browser is dropped
+        socket.set_read_timeout(Some(BEACON_INTERVAL))?;
+
+        let casters = Arc::new(Mutex::new(HashMap::new()));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let casters_clone = casters.clone();
+        let running_clone = running.clone();
+        thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            while running_clone.load(Ordering::Relaxed) {
+                let (len, source) = match socket.recv_from(&mut buffer) {
+                    Ok(received) => received,
+                    Err(_) => continue,
+                };
+                let beacon = match Beacon::decode(&buffer[..len]) {
+                    Some(beacon) if beacon.version == PROTOCOL_VERSION => beacon,
+                    _ => continue,
+                };
+                let info = CasterInfo {
+                    ip: source.ip(),
+                    port: beacon.port,
+                    name: beacon.name,
+                    passphrase_required: beacon.passphrase_required,
+                    tls: beacon.tls,
+                };
+                // the same beacon arrives twice, through multicast and broadcast
+                casters_clone
+                    .lock()
+                    .unwrap()
+                    .insert(SocketAddr::new(info.ip, info.port), (info, Instant::now()));
+            }
+        });
+
+        Ok(Self { casters, running })
+    }
+
+    /// Casters heard in the last few seconds, sorted by name
+    pub fn casters(&self) -> Vec<CasterInfo> {
+        let mut casters = self.casters.lock().unwrap();
+        casters.retain(|_, (_, seen)| seen.elapsed() < EXPIRATION);
+        let mut casters: Vec<CasterInfo> = casters.values().map(|(info, _)| info.clone()).collect();
+        casters.sort_by(|a, b| a.name.cmp(&b.name).then(a.ip.cmp(&b.ip)));
+        casters
+    }
+}
+
+impl Drop for CasterBrowser {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn beacon() -> Beacon {
+        Beacon {
+            version: PROTOCOL_VERSION,
+            name: "caster".to_string(),
+            port: 9000,
+            passphrase_required: true,
+            tls: false,
+        }
+    }
+
+    #[test]
+    fn beacon_survives_encoding() {
+        let data = beacon().encode();
+        assert!(data.starts_with(MAGIC));
+        assert_eq!(Beacon::decode(&data), Some(beacon()));
+    }
+
+    #[test]
+    fn foreign_datagrams_are_ignored() {
+        let data = beacon().encode();
+        assert_eq!(Beacon::decode(&data[MAGIC.len()..]), None);
+        assert_eq!(Beacon::decode(&data[..data.len() - 1]), None);
+        assert_eq!(Beacon::decode(MAGIC), None);
+        assert_eq!(Beacon::decode(b""), None);
+    }
+}
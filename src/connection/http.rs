@@ -0,0 +1,190 @@
+//! Minimal HTTP/1.1 server for the outputs watched from a browser. Only `GET`
+//! and `HEAD` are supported, every response closes the connection.
+
+use super::listen_dual_stack;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Requests whose head doesn't arrive within this are dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest request head accepted
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+pub struct Request<'a> {
+    /// Without the query, which is ignored
+    pub path: &'a str,
+}
+
+/// Writes a body whose length isn't known in advance
+pub type BodyWriter = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send>;
+
+pub enum Body {
+    Full(Vec<u8>),
+    /// Written until it returns, e.g. when the browser goes away and a write fails
+    Stream(BodyWriter),
+}
+
+pub struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Body,
+}
+
+impl Response {
+    pub fn new(content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status: "200 OK",
+            content_type,
+            body: Body::Full(body.into()),
+        }
+    }
+
+    pub fn stream(
+        content_type: &'static str,
+        write: impl FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+    ) -> Self {
+        Self {
+            status: "200 OK",
+            content_type,
+            body: Body::Stream(Box::new(write)),
+        }
+    }
+
+    /// Plain text response with the status as body, e.g. "403 Forbidden"
+    pub fn error(status: &'static str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: Body::Full(status.into()),
+        }
+    }
+}
+
+/// Serves the responses of `handler` until dropped
+pub struct HttpServer {
+    running: Arc<AtomicBool>,
+}
+
+impl HttpServer {
+    /// `handler` gets every request and returns None when there is nothing at its path
+    pub fn new(
+        port: u16,
+        handler: impl Fn(&Request) -> Option<Response> + Send + Sync + 'static,
+    ) -> io::Result<Self> {
+        let listeners = listen_dual_stack(port, TcpListener::bind)?;
+        for listener in &listeners {
+            listener.set_nonblocking(true)?;
+        }
+
+        let handler = Arc::new(handler);
+        let running = Arc::new(AtomicBool::new(true));
+        let running_clone = running.clone();
+        thread::spawn(move || {
+            while running_clone.load(Ordering::Relaxed) {
+                let accepted = listeners
+                    .iter()
+                    .find_map(|listener| match listener.accept() {
+                        Ok(accepted) => Some(accepted),
+                        Err(e) => {
+                            if e.kind() != io::ErrorKind::WouldBlock {
+                                println!("HTTP accept error: {}", e);
+                            }
+                            None
+                        }
+                    });
+                let Some((stream, remote)) = accepted else {
+                    thread::sleep(Duration::from_millis(50));
+                    continue;
+                };
+                let handler = handler.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, handler.as_ref()) {
+                        println!("HTTP request from {} failed: {}", remote, e);
+                    }
+                });
+            }
+        });
+
+        Ok(Self { running })
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+fn serve(stream: TcpStream, handler: &dyn Fn(&Request) -> Option<Response>) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    // nothing past the longest head is read, however long its lines are
+    let started = Instant::now();
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_HEAD_SIZE as u64));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers are read only to be skipped, none of them is used
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            if reader.get_ref().limit() == 0 {
+                return respond(
+                    stream,
+                    Response::error("431 Request Header Fields Too Large"),
+                    false,
+                );
+            }
+            break;
+        }
+        if header.trim_end().is_empty() {
+            break;
+        }
+        // a line at a time, within the read timeout, would keep the thread forever
+        if started.elapsed() > REQUEST_TIMEOUT {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return respond(stream, Response::error("400 Bad Request"), false);
+    };
+    let head = match method {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return respond(stream, Response::error("405 Method Not Allowed"), false),
+    };
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+    let response = handler(&Request { path }).unwrap_or_else(|| Response::error("404 Not Found"));
+    respond(stream, response, head)
+}
+
+fn respond(mut stream: TcpStream, response: Response, head: bool) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n",
+        response.status, response.content_type
+    )?;
+    match response.body {
+        Body::Full(body) => {
+            write!(stream, "Content-Length: {}\r\n\r\n", body.len())?;
+            if !head {
+                stream.write_all(&body)?;
+            }
+        }
+        // without a length the end of the body is the end of the connection
+        Body::Stream(write) => {
+            stream.write_all(b"\r\n")?;
+            if !head {
+                write(&mut stream)?;
+            }
+        }
+    }
+    stream.flush()
+}
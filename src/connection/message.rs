@@ -0,0 +1,217 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+
+/// Version of the control protocol spoken over the signaling websocket.
+/// Peers announcing a different version in their `Hello` are rejected.
+pub const PROTOCOL_VERSION: u16 = 15;
+
+/// First message sent by a receiver after the websocket is open
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hello {
+    pub version: u16,
+    pub name: String,
+    /// UDP port the receiver is listening on for the RTP stream, RTCP uses the next one
+    pub media_port: u16,
+}
+
+impl Hello {
+    pub fn new<T: Into<String>>(name: T, media_port: u16) -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            name: name.into(),
+            media_port,
+        }
+    }
+
+    /// Reason a caster refuses this hello, None when the versions match
+    pub fn version_error(&self) -> Option<String> {
+        (self.version != PROTOCOL_VERSION).then(|| {
+            format!(
+                "Unsupported protocol version {} (expected {})",
+                self.version, PROTOCOL_VERSION
+            )
+        })
+    }
+}
+
+/// How the caster delivers the media to a receiver
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum MediaTransport {
+    /// RTP datagrams to the media port announced in the `Hello`
+    Udp,
+    /// RTP datagrams to the multicast group announced in the `StreamInfo`
+    Multicast,
+    /// RTP packets as `ControlMessage::Media` over the signaling websocket,
+    /// for networks where UDP is filtered
+    Websocket,
+}
+
+/// Quality of the media received over UDP since the previous report
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ReceptionReport {
+    /// Lost packets over expected ones, from 0.0 to 1.0
+    pub fraction_lost: f32,
+    /// Average interarrival jitter in milliseconds
+    pub jitter_ms: f32,
+    /// Time the receiver's pipeline holds the media before showing it, in milliseconds
+    pub latency_ms: f32,
+    /// SSRC of the receiver's RTCP, the caster matches its round trip with it
+    /// (receivers behind the same NAT, or on the same host, share the address)
+    pub ssrc: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ControlMessage {
+    Hello(Hello),
+
+    /// Sent by a caster protected by a passphrase in reply to a `Hello`
+    AuthChallenge {
+        nonce: Vec<u8>,
+    },
+    /// The receiver proves it knows the passphrase, see `auth_digest`
+    AuthResponse {
+        digest: Vec<u8>,
+    },
+    AuthRejected(String),
+
+    /// The caster has to allow the receiver before the stream is sent
+    AwaitingApproval,
+    Rejected(String),
+
+    /// Sent by the caster to a receiver once its `Hello` has been accepted
+    StreamInfo {
+        codec: String,
+        framerate: u32,
+        paused: bool,
+        blanked: bool,
+        /// Master key and salt of the SRTP media (AES-128-ICM, HMAC-SHA1-80),
+        /// None when the media is plain RTP. The RTCP reports are never encrypted.
+        /// Only confidential when the signaling uses TLS.
+        srtp_key: Option<Vec<u8>>,
+        /// Group and port to join when the caster multicasts the media
+        multicast: Option<SocketAddr>,
+        /// UDP port of the caster the RTCP reports and retransmission requests go to
+        rtcp_port: u16,
+        /// Whether an Opus stream is sent next to the video, see `streaming::audio`
+        audio: bool,
+    },
+
+    /// Sent by a receiver to change how it gets the media
+    SetMediaTransport(MediaTransport),
+    /// A (S)RTP packet, when the receiver asked for `MediaTransport::Websocket`
+    Media(Vec<u8>),
+    /// Sent every second by a receiver getting the media over UDP,
+    /// the caster adapts the bitrate to the worst one
+    ReceptionReport(ReceptionReport),
+    /// Sent by a relay when one of its receivers joins, so that it doesn't wait for the next IDR
+    RequestKeyframe,
+
+    Paused,
+    Resumed,
+    Blanked,
+    Restored,
+
+    /// Sent periodically by both sides so that dead peers can be detected
+    Heartbeat,
+
+    /// Sent periodically by a receiver, with the time of its clock in microseconds
+    /// since the UNIX epoch, to estimate the offset of the caster's clock
+    Ping {
+        sent_us: u64,
+    },
+    /// The caster's answer, with the time of the ping and its own time
+    Pong {
+        sent_us: u64,
+        caster_us: u64,
+    },
+
+    /// Sent by either side right before closing the connection on purpose
+    Goodbye,
+
+    Error(String),
+}
+
+/// Length of the SRTP master key (16 bytes) followed by the master salt (14 bytes)
+pub const SRTP_KEY_LEN: usize = 30;
+
+/// SHA-256 of the challenge nonce followed by the shared secret
+pub fn auth_digest(nonce: &[u8], secret: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(nonce);
+    hasher.update(secret.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+impl ControlMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        // serializing a plain enum into memory can't fail
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, bincode::Error> {
+        bincode::deserialize(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_survive_encoding() {
+        let messages = [
+            ControlMessage::Hello(Hello::new("receiver", 9001)),
+            ControlMessage::AuthChallenge { nonce: vec![1; 32] },
+            ControlMessage::StreamInfo {
+                codec: "H264".to_string(),
+                framerate: 30,
+                paused: false,
+                blanked: true,
+                srtp_key: Some(vec![7; SRTP_KEY_LEN]),
+                multicast: Some("239.255.42.100:9011".parse().unwrap()),
+                rtcp_port: 9005,
+                audio: true,
+            },
+            ControlMessage::ReceptionReport(ReceptionReport {
+                fraction_lost: 0.25,
+                jitter_ms: 3.5,
+                latency_ms: 200.0,
+                ssrc: 0xdead_beef,
+            }),
+            ControlMessage::Pong {
+                sent_us: 1,
+                caster_us: u64::MAX,
+            },
+            ControlMessage::Goodbye,
+        ];
+        for message in messages {
+            assert_eq!(ControlMessage::decode(&message.encode()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn garbage_is_not_a_message() {
+        assert!(ControlMessage::decode(&[0xff; 3]).is_err());
+        assert!(ControlMessage::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn other_versions_are_refused() {
+        assert_eq!(Hello::new("receiver", 9001).version_error(), None);
+        let hello = Hello {
+            version: PROTOCOL_VERSION - 1,
+            ..Hello::new("receiver", 9001)
+        };
+        assert!(hello.version_error().is_some());
+    }
+
+    #[test]
+    fn digest_depends_on_nonce_and_secret() {
+        let digest = auth_digest(b"nonce", "secret");
+        assert_eq!(digest.len(), 32);
+        assert_eq!(digest, auth_digest(b"nonce", "secret"));
+        assert_ne!(digest, auth_digest(b"other", "secret"));
+        assert_ne!(digest, auth_digest(b"nonce", "Secret"));
+    }
+}
@@ -1,41 +1,359 @@
-use message_io::network::{NetEvent, Transport};
-use message_io::node::{self, NodeHandler};
+use super::clock::now_micros;
+use super::message::{auth_digest, ControlMessage, Hello};
+use super::tls::{TlsAcceptor, TlsIdentity};
+use super::viewer::{Viewer, ViewerConfig};
+use super::{listen_dual_stack, Signal, DEFAULT_SIGNALING_PORT, DEFAULT_TIMEOUT};
+use message_io::network::{Endpoint, NetEvent, Transport};
+use message_io::node::{self, NodeEvent, NodeHandler};
+use rand::RngCore;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{io, thread};
 
+/// Cloneable handle used to talk to the receivers, also given to the callbacks
+#[derive(Clone)]
+pub struct ServerHandle {
+    ws_handler: NodeHandler<Signal>,
+    peers: Arc<Mutex<HashMap<Endpoint, Hello>>>,
+    tls: Option<Arc<TlsAcceptor>>,
+    viewer: Option<Arc<Viewer>>,
+}
+
+impl ServerHandle {
+    pub fn send(&self, endpoint: Endpoint, message: &ControlMessage) {
+        self.ws_handler.network().send(endpoint, &message.encode());
+    }
+
+    /// Sends the message to every receiver that completed the hello
+    pub fn broadcast(&self, message: &ControlMessage) {
+        let data = message.encode();
+        for endpoint in self.peers.lock().unwrap().keys() {
+            self.ws_handler.network().send(*endpoint, &data);
+        }
+    }
+
+    /// Closes the connection without notifying the on_disconnect callback
+    pub fn disconnect(&self, endpoint: Endpoint) {
+        self.peers.lock().unwrap().remove(&endpoint);
+        self.ws_handler.network().remove(endpoint.resource_id());
+    }
+
+    /// Address of the receiver, `endpoint.addr()` is the local proxy when TLS is enabled.
+    /// IPv4 receivers accepted by a dual-stack socket get their plain IPv4 address.
+    pub fn peer_addr(&self, endpoint: Endpoint) -> SocketAddr {
+        let addr = match &self.tls {
+            Some(tls) => tls.peer_addr(endpoint.addr()),
+            None => endpoint.addr(),
+        };
+        SocketAddr::new(addr.ip().to_canonical(), addr.port())
+    }
+
+    /// Offers a JPEG frame to the browser viewer, if enabled
+    pub fn publish_frame(&self, jpeg: &[u8]) {
+        if let Some(viewer) = &self.viewer {
+            viewer.publish(jpeg);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectionServerConfig {
+    pub port: u16,
+    /// When set receivers must answer a challenge with the same passphrase
+    /// before being reported through on_connect
+    pub secret: Option<String>,
+    /// Receivers that don't send anything for longer than this are dropped
+    pub timeout: Duration,
+    /// When set the websocket is only reachable through TLS (wss)
+    pub tls: Option<TlsIdentity>,
+    /// When set a page showing the frames given to `ServerHandle::publish_frame`
+    /// is served to browsers, without any admission
+    pub viewer: Option<ViewerConfig>,
+}
+
+impl Default for ConnectionServerConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_SIGNALING_PORT,
+            secret: None,
+            timeout: DEFAULT_TIMEOUT,
+            tls: None,
+            viewer: None,
+        }
+    }
+}
+
 pub struct ConnectionServer {
-    ws_handler: NodeHandler<()>,
+    handle: ServerHandle,
 }
 
 impl ConnectionServer {
     pub fn new(
-        mut on_connect: impl FnMut(&str) -> () + Send + 'static,
-        mut on_disconnect: impl FnMut(&str) -> () + Send + 'static,
+        config: ConnectionServerConfig,
+        mut on_connect: impl FnMut(&ServerHandle, Endpoint, &Hello) + Send + 'static,
+        mut on_message: impl FnMut(&ServerHandle, Endpoint, ControlMessage) + Send + 'static,
+        mut on_disconnect: impl FnMut(Endpoint, &Hello) + Send + 'static,
     ) -> io::Result<Self> {
-        let (ws_handler, listener) = node::split::<()>();
+        let (ws_handler, listener) = node::split::<Signal>();
 
-        ws_handler.network().listen(Transport::Ws, "0.0.0.0:9000")?;
+        let ConnectionServerConfig {
+            port,
+            secret,
+            timeout,
+            tls,
+            viewer,
+        } = config;
 
+        let tls = match tls {
+            Some(identity) => {
+                // the websocket is only reachable locally, through the TLS proxy
+                let (_, local) = ws_handler.network().listen(Transport::Ws, "127.0.0.1:0")?;
+                let acceptor = TlsAcceptor::new(&identity, port, local)?;
+                println!("TLS certificate fingerprint: {}", acceptor.fingerprint());
+                Some(Arc::new(acceptor))
+            }
+            None => {
+                listen_dual_stack(port, |addr| {
+                    ws_handler.network().listen(Transport::Ws, addr)
+                })?;
+                None
+            }
+        };
+
+        let viewer = match viewer {
+            Some(viewer) => Some(Arc::new(Viewer::new(&viewer)?)),
+            None => None,
+        };
+
+        let handle = ServerHandle {
+            ws_handler,
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            tls,
+            viewer,
+        };
+
+        handle
+            .ws_handler
+            .signals()
+            .send_with_timer(Signal::Heartbeat, timeout / 3);
+
+        let handle_clone = handle.clone();
         thread::spawn(move || {
-            listener.for_each(move |event| match event.network() {
-                NetEvent::Connected(..) => unreachable!(),
-                NetEvent::Accepted(endpoint, _) => {
-                    let ip = endpoint.addr().ip().to_string();
-                    on_connect(&ip);
-                }
-                NetEvent::Message(..) => println!("Message"),
-                NetEvent::Disconnected(endpoint) => {
-                    let ip = endpoint.addr().ip().to_string();
-                    on_disconnect(&ip);
+            let handle = handle_clone;
+            // receivers that still have to answer the challenge, with the nonce sent to them
+            let mut pending: HashMap<Endpoint, (Hello, Vec<u8>)> = HashMap::new();
+            // when something was last received from every open connection
+            let mut last_seen: HashMap<Endpoint, Instant> = HashMap::new();
+            listener.for_each(move |event| match event {
+                NodeEvent::Network(event) => match event {
+                    NetEvent::Connected(..) => unreachable!(),
+                    // a receiver is only considered connected after a valid hello
+                    NetEvent::Accepted(endpoint, _) => {
+                        last_seen.insert(endpoint, Instant::now());
+                    }
+                    NetEvent::Message(endpoint, data) => {
+                        last_seen.insert(endpoint, Instant::now());
+                        match ControlMessage::decode(data) {
+                            Ok(ControlMessage::Hello(hello)) => {
+                                if let Some(error) = hello.version_error() {
+                                    handle.send(endpoint, &ControlMessage::Error(error));
+                                    handle.disconnect(endpoint);
+                                } else if secret.is_some() {
+                                    let mut nonce = vec![0; 32];
+                                    rand::thread_rng().fill_bytes(&mut nonce);
+                                    handle.send(
+                                        endpoint,
+                                        &ControlMessage::AuthChallenge {
+                                            nonce: nonce.clone(),
+                                        },
+                                    );
+                                    pending.insert(endpoint, (hello, nonce));
+                                } else {
+                                    let known = handle
+                                        .peers
+                                        .lock()
+                                        .unwrap()
+                                        .insert(endpoint, hello.clone());
+                                    if known.is_none() {
+                                        on_connect(&handle, endpoint, &hello);
+                                    }
+                                }
+                            }
+                            Ok(ControlMessage::AuthResponse { digest }) => {
+                                if let (Some((hello, nonce)), Some(secret)) =
+                                    (pending.remove(&endpoint), &secret)
+                                {
+                                    if digest_eq(&digest, &auth_digest(&nonce, secret)) {
+                                        // a second hello on the same connection only updates it
+                                        let known = handle
+                                            .peers
+                                            .lock()
+                                            .unwrap()
+                                            .insert(endpoint, hello.clone());
+                                        if known.is_none() {
+                                            on_connect(&handle, endpoint, &hello);
+                                        }
+                                    } else {
+                                        println!(
+                                            "Authentication failed: {}",
+                                            handle.peer_addr(endpoint)
+                                        );
+                                        handle.send(
+                                            endpoint,
+                                            &ControlMessage::AuthRejected(
+                                                "Wrong passphrase".to_string(),
+                                            ),
+                                        );
+                                        handle.disconnect(endpoint);
+                                    }
+                                }
+                            }
+                            Ok(ControlMessage::Heartbeat) => {}
+                            // the clock is only shared with the authenticated receivers
+                            Ok(ControlMessage::Ping { sent_us }) => {
+                                if handle.peers.lock().unwrap().contains_key(&endpoint) {
+                                    handle.send(
+                                        endpoint,
+                                        &ControlMessage::Pong {
+                                            sent_us,
+                                            caster_us: now_micros(),
+                                        },
+                                    );
+                                }
+                            }
+                            Ok(ControlMessage::Goodbye) => {
+                                let hello = handle.peers.lock().unwrap().remove(&endpoint);
+                                if let Some(hello) = hello {
+                                    on_disconnect(endpoint, &hello);
+                                }
+                            }
+                            Ok(message) => {
+                                if handle.peers.lock().unwrap().contains_key(&endpoint) {
+                                    on_message(&handle, endpoint, message);
+                                }
+                            }
+                            Err(e) => println!(
+                                "Invalid message from {}: {}",
+                                handle.peer_addr(endpoint),
+                                e
+                            ),
+                        }
+                    }
+                    NetEvent::Disconnected(endpoint) => {
+                        last_seen.remove(&endpoint);
+                        pending.remove(&endpoint);
+                        let hello = handle.peers.lock().unwrap().remove(&endpoint);
+                        if let Some(hello) = hello {
+                            on_disconnect(endpoint, &hello);
+                        }
+                    }
+                },
+                NodeEvent::Signal(Signal::Heartbeat) => {
+                    // forget connections closed on our side through ServerHandle::disconnect
+                    last_seen.retain(|endpoint, _| {
+                        handle
+                            .ws_handler
+                            .network()
+                            .is_ready(endpoint.resource_id())
+                            .is_some()
+                    });
+                    pending.retain(|endpoint, _| last_seen.contains_key(endpoint));
+                    let timed_out: Vec<Endpoint> = last_seen
+                        .iter()
+                        .filter(|(_, seen)| seen.elapsed() > timeout)
+                        .map(|(endpoint, _)| *endpoint)
+                        .collect();
+                    for endpoint in timed_out {
+                        println!("Receiver timed out: {}", handle.peer_addr(endpoint));
+                        last_seen.remove(&endpoint);
+                        pending.remove(&endpoint);
+                        handle.ws_handler.network().remove(endpoint.resource_id());
+                        let hello = handle.peers.lock().unwrap().remove(&endpoint);
+                        if let Some(hello) = hello {
+                            on_disconnect(endpoint, &hello);
+                        }
+                    }
+                    handle.broadcast(&ControlMessage::Heartbeat);
+                    handle
+                        .ws_handler
+                        .signals()
+                        .send_with_timer(Signal::Heartbeat, timeout / 3);
                 }
+                // only the receivers ping, the caster answers
+                NodeEvent::Signal(Signal::Ping) => {}
             });
         });
 
-        Ok(Self { ws_handler })
+        Ok(Self { handle })
+    }
+
+    pub fn send(&self, endpoint: Endpoint, message: &ControlMessage) {
+        self.handle.send(endpoint, message);
+    }
+
+    pub fn broadcast(&self, message: &ControlMessage) {
+        self.handle.broadcast(message);
+    }
+
+    pub fn disconnect(&self, endpoint: Endpoint) {
+        self.handle.disconnect(endpoint);
+    }
+
+    pub fn peer_addr(&self, endpoint: Endpoint) -> SocketAddr {
+        self.handle.peer_addr(endpoint)
+    }
+
+    /// Handle that can be moved to other threads, e.g. to send media from the pipeline
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// SHA-256 fingerprint of the certificate, receivers can compare it with the pinned one
+    pub fn fingerprint(&self) -> Option<&str> {
+        self.handle.tls.as_ref().map(|tls| tls.fingerprint())
+    }
+
+    /// Address of the browser viewer, when enabled
+    pub fn viewer_url(&self) -> Option<String> {
+        self.handle.viewer.as_ref().map(|viewer| viewer.url())
+    }
+
+    /// Browsers watching through the viewer
+    pub fn viewers(&self) -> usize {
+        self.handle
+            .viewer
+            .as_ref()
+            .map_or(0, |viewer| viewer.viewers())
     }
 }
 
+/// Compares the digests without stopping at the first differing byte
+fn digest_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 impl Drop for ConnectionServer {
     fn drop(&mut self) {
-        self.ws_handler.stop();
+        self.handle.broadcast(&ControlMessage::Goodbye);
+        self.handle.ws_handler.stop();
+        if let Some(viewer) = &self.handle.viewer {
+            viewer.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests_compare_by_content() {
+        let digest = auth_digest(b"nonce", "secret");
+        assert!(digest_eq(&digest, &auth_digest(b"nonce", "secret")));
+        assert!(!digest_eq(&digest, &auth_digest(b"nonce", "wrong")));
+        assert!(!digest_eq(&digest, &digest[..31]));
+        assert!(digest_eq(&[], &[]));
     }
 }
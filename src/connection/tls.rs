@@ -0,0 +1,437 @@
+//! TLS for the signaling websocket.
+//!
+//! message-io only speaks plain websockets, so TLS is terminated by a small
+//! proxy on each side: the caster accepts TLS connections and forwards them to
+//! a websocket listener bound to loopback, the receiver connects to a loopback
+//! listener that forwards to the caster over TLS.
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, ClientConnection, PrivateKey, ServerConfig};
+use rustls::{ServerConnection, ServerName};
+use sha2::{Digest, Sha256};
+
+use super::{host_port, listen_dual_stack};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+/// Name used for SNI and for generated certificates, receivers pin the
+/// certificate so it doesn't have to match the caster's address
+const SERVER_NAME: &str = "rust-streamer";
+
+/// Size of the reads of both sides of the proxies
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Certificate presented by the caster
+#[derive(Clone, Debug)]
+pub enum TlsIdentity {
+    /// A self-signed certificate generated at the first start and kept in these
+    /// PEM files, so that the receivers that pinned it keep accepting it
+    SelfSigned { cert: PathBuf, key: PathBuf },
+    /// PEM encoded certificate chain and PKCS#8 or RSA private key
+    Pem { cert: PathBuf, key: PathBuf },
+}
+
+/// Directory of the files kept between runs, `rust-streamer` in the platform
+/// config directory (e.g. `~/.config` on Linux)
+fn config_dir() -> PathBuf {
+    dirs::config_dir().unwrap_or_default().join(SERVER_NAME)
+}
+
+/// Creates the directory of `path` if it's missing
+fn create_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => fs::create_dir_all(parent),
+        _ => Ok(()),
+    }
+}
+
+impl TlsIdentity {
+    /// Self-signed certificate kept in the config directory, like `known_casters`
+    pub fn self_signed() -> Self {
+        let dir = config_dir();
+        Self::SelfSigned {
+            cert: dir.join("caster_cert.pem"),
+            key: dir.join("caster_key.pem"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TlsClientConfig {
+    /// File with the fingerprints of the casters seen so far, one `host:port fingerprint` per line
+    pub known_casters: PathBuf,
+}
+
+impl Default for TlsClientConfig {
+    fn default() -> Self {
+        Self {
+            known_casters: config_dir().join("known_casters"),
+        }
+    }
+}
+
+impl TlsClientConfig {
+    /// Forgets the certificate pinned for the caster, the one it presents at
+    /// the next connection is pinned instead (e.g. after it got a new one)
+    pub fn forget(&self, host: &str, port: u16) -> io::Result<()> {
+        let caster = host_port(host, port);
+        let known = match fs::read_to_string(&self.known_casters) {
+            Ok(known) => known,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let kept: String = known
+            .lines()
+            .filter(|line| line.split_once(' ').map(|(host, _)| host) != Some(&caster))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        fs::write(&self.known_casters, kept)
+    }
+}
+
+/// SHA-256 of the DER certificate, as colon separated hex
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn tls_error(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn load_identity(identity: &TlsIdentity) -> io::Result<(Vec<Certificate>, PrivateKey)> {
+    match identity {
+        TlsIdentity::SelfSigned { cert, key } => {
+            if !cert.exists() || !key.exists() {
+                generate_self_signed(cert, key)?;
+                println!("Self-signed certificate written to {}", cert.display());
+            }
+            load_pem(cert, key)
+        }
+        TlsIdentity::Pem { cert, key } => load_pem(cert, key),
+    }
+}
+
+/// Writes a new self-signed certificate and its key, only the owner can read the key
+fn generate_self_signed(cert_path: &Path, key_path: &Path) -> io::Result<()> {
+    let cert =
+        rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()]).map_err(tls_error)?;
+    create_parent(key_path)?;
+    create_parent(cert_path)?;
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options
+        .open(key_path)?
+        .write_all(cert.serialize_private_key_pem().as_bytes())?;
+    // the certificate is read back from the file, every serialization has a new signature
+    fs::write(cert_path, cert.serialize_pem().map_err(tls_error)?)
+}
+
+fn load_pem(cert: &Path, key: &Path) -> io::Result<(Vec<Certificate>, PrivateKey)> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(fs::File::open(cert)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(tls_error(format!("no certificate in {}", cert.display())));
+    }
+    let mut reader = BufReader::new(fs::File::open(key)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok((certs, PrivateKey(key))),
+            Some(_) => {}
+            None => return Err(tls_error(format!("no private key in {}", key.display()))),
+        }
+    }
+}
+
+/// Accepts TLS connections and forwards them to the plain websocket listener
+pub struct TlsAcceptor {
+    fingerprint: String,
+    /// Loopback address of each forwarded connection mapped to the real peer address
+    peers: Arc<Mutex<HashMap<SocketAddr, SocketAddr>>>,
+    running: Arc<AtomicBool>,
+}
+
+impl TlsAcceptor {
+    pub fn new(identity: &TlsIdentity, port: u16, target: SocketAddr) -> io::Result<Self> {
+        let (certs, key) = load_identity(identity)?;
+        let fingerprint = fingerprint(&certs[0].0);
+        let config = Arc::new(
+            ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .map_err(tls_error)?,
+        );
+
+        let listeners = listen_dual_stack(port, TcpListener::bind)?;
+        for listener in &listeners {
+            listener.set_nonblocking(true)?;
+        }
+
+        let peers = Arc::new(Mutex::new(HashMap::new()));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let peers_clone = peers.clone();
+        let running_clone = running.clone();
+        thread::spawn(move || {
+            while running_clone.load(Ordering::Relaxed) {
+                let accepted = listeners
+                    .iter()
+                    .find_map(|listener| match listener.accept() {
+                        Ok(accepted) => Some(accepted),
+                        Err(e) => {
+                            if e.kind() != io::ErrorKind::WouldBlock {
+                                println!("TLS accept error: {}", e);
+                            }
+                            None
+                        }
+                    });
+                let Some((stream, remote)) = accepted else {
+                    thread::sleep(Duration::from_millis(50));
+                    continue;
+                };
+                let config = config.clone();
+                let peers = peers_clone.clone();
+                thread::spawn(move || {
+                    let result = (|| -> io::Result<()> {
+                        stream.set_nonblocking(false)?;
+                        let connection = ServerConnection::new(config).map_err(tls_error)?;
+                        let plain = TcpStream::connect(target)?;
+                        let local = plain.local_addr()?;
+                        peers.lock().unwrap().insert(local, remote);
+                        let result = pump(connection, stream, plain);
+                        peers.lock().unwrap().remove(&local);
+                        result
+                    })();
+                    if let Err(e) = result {
+                        println!("TLS connection with {} closed: {}", remote, e);
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            fingerprint,
+            peers,
+            running,
+        })
+    }
+
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Translates the loopback address seen by message-io into the real peer address
+    pub fn peer_addr(&self, local: SocketAddr) -> SocketAddr {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(&local)
+            .copied()
+            .unwrap_or(local)
+    }
+}
+
+impl Drop for TlsAcceptor {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Trust on first use: unknown casters are remembered, known ones must present the same certificate
+struct PinnedVerifier {
+    caster: String,
+    known_casters: PathBuf,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = fingerprint(&end_entity.0);
+        match pinned_fingerprint(&self.known_casters, &self.caster) {
+            Some(pinned) if pinned == fingerprint => Ok(ServerCertVerified::assertion()),
+            Some(pinned) => Err(rustls::Error::General(format!(
+                "the certificate of {} changed (pinned {}, received {}), forget the pinned one if the caster got a new certificate",
+                self.caster, pinned, fingerprint
+            ))),
+            None => {
+                println!("Pinning certificate of {}: {}", self.caster, fingerprint);
+                pin_fingerprint(&self.known_casters, &self.caster, &fingerprint)
+                    .map_err(|e| rustls::Error::General(e.to_string()))?;
+                Ok(ServerCertVerified::assertion())
+            }
+        }
+    }
+}
+
+fn pinned_fingerprint(known_casters: &Path, caster: &str) -> Option<String> {
+    fs::read_to_string(known_casters)
+        .ok()?
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(host, _)| *host == caster)
+        .map(|(_, fingerprint)| fingerprint.trim().to_string())
+}
+
+fn pin_fingerprint(known_casters: &Path, caster: &str, fingerprint: &str) -> io::Result<()> {
+    create_parent(known_casters)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(known_casters)?;
+    writeln!(file, "{} {}", caster, fingerprint)
+}
+
+/// Opens a TLS connection to the caster and returns the loopback address
+/// message-io has to connect to. Fails if the certificate doesn't match the one
+/// pinned for `caster`, the address as given by the user.
+pub fn connect(
+    address: SocketAddr,
+    caster: &str,
+    config: &TlsClientConfig,
+) -> io::Result<SocketAddr> {
+    let verifier = PinnedVerifier {
+        caster: caster.to_string(),
+        known_casters: config.known_casters.clone(),
+    };
+    let client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    let mut connection = ClientConnection::new(
+        Arc::new(client_config),
+        ServerName::try_from(SERVER_NAME).unwrap(),
+    )
+    .map_err(tls_error)?;
+
+    let mut stream = TcpStream::connect(address)?;
+    // complete the handshake now so that certificate errors are reported to the caller
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream)?;
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let local = listener.local_addr()?;
+    thread::spawn(move || {
+        let result = listener
+            .accept()
+            .and_then(|(plain, _)| pump(connection, stream, plain));
+        if let Err(e) = result {
+            println!("TLS connection closed: {}", e);
+        }
+    });
+
+    Ok(local)
+}
+
+/// Copies data both ways until one of the sides is closed, the plain side is
+/// read on a thread of its own while the TLS one is read on the calling thread
+fn pump<C, D>(connection: C, mut tls: TcpStream, mut plain: TcpStream) -> io::Result<()>
+where
+    C: std::ops::DerefMut<Target = rustls::ConnectionCommon<D>> + Send + 'static,
+    D: rustls::SideData,
+{
+    let connection = Arc::new(Mutex::new(connection));
+
+    let connection_clone = connection.clone();
+    let mut tls_clone = tls.try_clone()?;
+    let mut plain_clone = plain.try_clone()?;
+    let outgoing = thread::spawn(move || -> io::Result<()> {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            let n = plain_clone.read(&mut buffer)?;
+            let mut connection = connection_clone.lock().unwrap();
+            if n == 0 {
+                connection.send_close_notify();
+            } else {
+                connection.writer().write_all(&buffer[..n])?;
+            }
+            while connection.wants_write() {
+                connection.write_tls(&mut tls_clone)?;
+            }
+            if n == 0 {
+                // also ends the reads of the other direction
+                let _ = tls_clone.shutdown(Shutdown::Both);
+                return Ok(());
+            }
+        }
+    });
+
+    let incoming = (|| -> io::Result<()> {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            let n = match tls.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let mut received = &buffer[..n];
+            let mut plaintext = Vec::new();
+            let mut closed = false;
+            {
+                let mut connection = connection.lock().unwrap();
+                while !received.is_empty() {
+                    connection.read_tls(&mut received)?;
+                    let state = connection.process_new_packets().map_err(tls_error)?;
+                    let start = plaintext.len();
+                    plaintext.resize(start + state.plaintext_bytes_to_read(), 0);
+                    connection.reader().read_exact(&mut plaintext[start..])?;
+                    closed |= state.peer_has_closed();
+                }
+                // alerts and key updates
+                while connection.wants_write() {
+                    connection.write_tls(&mut tls)?;
+                }
+            }
+            // written without the lock, the other direction goes on meanwhile
+            plain.write_all(&plaintext)?;
+            if closed {
+                return Ok(());
+            }
+        }
+    })();
+
+    // the plain side is closed too, which stops the other thread
+    let _ = plain.shutdown(Shutdown::Both);
+    let outgoing = outgoing.join().unwrap();
+    incoming.and(outgoing.or_else(|e| {
+        // the sockets it used were shut down by this side
+        if matches!(
+            e.kind(),
+            io::ErrorKind::NotConnected
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+        ) {
+            Ok(())
+        } else {
+            Err(e)
+        }
+    }))
+}
@@ -0,0 +1,212 @@
+//! Browser viewer of the caster: a page showing the preview JPEG frames as an
+//! MJPEG stream, for the people who can't install the application. The frames
+//! are throttled, it's meant to follow slides rather than video.
+//!
+//! It's served over plain HTTP even when the signaling uses TLS, and the
+//! viewers aren't receivers: they don't need to be allowed and can't be kicked.
+//! So the caster refuses to serve it when the receivers are admitted with a
+//! passphrase or approval.
+
+use super::http::{HttpServer, Response};
+use super::{host_port, local_ip};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_VIEWER_PORT: u16 = 8081;
+
+/// The last frame is sent again when no new one came for this long, e.g. while
+/// the caster is paused, so that a browser that left is noticed by the write
+const RESEND_INTERVAL: Duration = Duration::from_secs(5);
+
+const VIEWER_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>rust-streamer</title>
+<style>
+body { margin: 0; background: black; color: white; font-family: sans-serif; }
+img { display: block; width: 100vw; height: 100vh; object-fit: contain; }
+p { position: fixed; top: 0; left: 1em; }
+</style>
+</head>
+<body>
+<img id="screen" alt="">
+<p id="status">Connecting...</p>
+<script>
+const screen = document.getElementById("screen");
+const status = document.getElementById("status");
+function watch() {
+  screen.src = "stream.mjpg?t=" + Date.now();
+}
+screen.onload = () => {
+  status.textContent = "";
+};
+screen.onerror = () => {
+  status.textContent = "Not available, retrying...";
+  setTimeout(watch, 2000);
+};
+watch();
+</script>
+</body>
+</html>
+"#;
+
+#[derive(Clone, Debug)]
+pub struct ViewerConfig {
+    /// Port of the HTTP server, the websocket's one is taken
+    pub port: u16,
+    /// Frames per second sent to the browsers at most
+    pub max_fps: u32,
+}
+
+impl Default for ViewerConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_VIEWER_PORT,
+            max_fps: 5,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Latest {
+    /// Incremented with every published frame, 0 before the first one
+    sequence: u64,
+    jpeg: Arc<Vec<u8>>,
+    published: Option<Instant>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Frames {
+    latest: Mutex<Latest>,
+    updated: Condvar,
+    viewers: AtomicUsize,
+}
+
+impl Frames {
+    /// Blocks until a frame newer than `sequence` is published, or returns the
+    /// same one again after `RESEND_INTERVAL`. None once closed.
+    fn next(&self, sequence: u64) -> Option<(u64, Arc<Vec<u8>>)> {
+        let (latest, _) = self
+            .updated
+            .wait_timeout_while(self.latest.lock().unwrap(), RESEND_INTERVAL, |latest| {
+                !latest.closed && latest.sequence <= sequence
+            })
+            .unwrap();
+        (!latest.closed).then(|| (latest.sequence, latest.jpeg.clone()))
+    }
+}
+
+/// Serves the page and the frames until closed
+pub(crate) struct Viewer {
+    port: u16,
+    interval: Duration,
+    frames: Arc<Frames>,
+    _http_server: HttpServer,
+}
+
+impl Viewer {
+    pub fn new(config: &ViewerConfig) -> io::Result<Self> {
+        let frames = Arc::new(Frames::default());
+
+        let frames_clone = frames.clone();
+        let http_server = HttpServer::new(config.port, move |request| match request.path {
+            "/" | "/index.html" => Some(Response::new("text/html; charset=utf-8", VIEWER_PAGE)),
+            "/stream.mjpg" => {
+                let frames = frames_clone.clone();
+                Some(Response::stream(
+                    "multipart/x-mixed-replace; boundary=frame",
+                    move |stream| {
+                        frames.viewers.fetch_add(1, Ordering::Relaxed);
+                        let result = send_frames(&frames, stream);
+                        frames.viewers.fetch_sub(1, Ordering::Relaxed);
+                        result
+                    },
+                ))
+            }
+            _ => None,
+        })?;
+
+        let viewer = Self {
+            port: config.port,
+            interval: Duration::from_secs(1) / config.max_fps.max(1),
+            frames,
+            _http_server: http_server,
+        };
+        println!("Browser viewer on {}", viewer.url());
+        Ok(viewer)
+    }
+
+    /// Offers a JPEG frame to the browsers, dropped when the previous one is too recent
+    pub fn publish(&self, jpeg: &[u8]) {
+        let mut latest = self.frames.latest.lock().unwrap();
+        if latest
+            .published
+            .is_some_and(|published| published.elapsed() < self.interval)
+        {
+            return;
+        }
+        latest.sequence += 1;
+        latest.jpeg = Arc::new(jpeg.to_vec());
+        latest.published = Some(Instant::now());
+        self.frames.updated.notify_all();
+    }
+
+    /// Browsers currently watching
+    pub fn viewers(&self) -> usize {
+        self.frames.viewers.load(Ordering::Relaxed)
+    }
+
+    /// URL of the page on the interface of the default route
+    pub fn url(&self) -> String {
+        let host = local_ip().map_or_else(|| "localhost".to_string(), |ip| ip.to_string());
+        format!("http://{}/", host_port(&host, self.port))
+    }
+
+    /// Ends the streams of the browsers watching
+    pub fn close(&self) {
+        self.frames.latest.lock().unwrap().closed = true;
+        self.frames.updated.notify_all();
+    }
+}
+
+fn send_frames(frames: &Frames, stream: &mut dyn Write) -> io::Result<()> {
+    let mut sequence = 0;
+    while let Some((next, jpeg)) = frames.next(sequence) {
+        sequence = next;
+        // nothing was published yet
+        if jpeg.is_empty() {
+            continue;
+        }
+        write!(
+            stream,
+            "--frame\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            jpeg.len()
+        )?;
+        stream.write_all(&jpeg)?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_returned_until_closed() {
+        let frames = Frames::default();
+        {
+            let mut latest = frames.latest.lock().unwrap();
+            latest.sequence = 1;
+            latest.jpeg = Arc::new(vec![0xff, 0xd8]);
+        }
+        assert_eq!(frames.next(0).map(|(sequence, _)| sequence), Some(1));
+        frames.latest.lock().unwrap().closed = true;
+        assert!(frames.next(1).is_none());
+    }
+}
@@ -1,9 +1,49 @@
+pub mod audio;
+pub mod bitrate;
 pub mod client;
+pub mod hls;
+pub mod latency;
+pub mod relay;
+pub mod rtmp;
+pub mod rtsp;
 pub mod server;
+pub mod srt;
+pub mod stats;
+
+use gstreamer::{self as gst, glib, prelude::*};
+
+use crate::connection::udp_wildcards;
+
+/// Caps of the caster's encoder output, shared by all the outputs
+pub(crate) const H264_CAPS: &str = "video/x-h264,stream-format=byte-stream,alignment=au";
+
+/// Feeds the funnel with a udpsrc per address family the host needs to get
+/// the packets sent to `port` over both IPv6 and IPv4
+pub(crate) fn listen_udp(
+    pipeline: &gst::Pipeline,
+    funnel: &str,
+    port: u16,
+) -> Result<(), glib::BoolError> {
+    let addresses = udp_wildcards(port)
+        .map_err(|e| glib::bool_error!("Failed to listen on UDP port {}: {}", port, e))?;
+    let funnel = pipeline.by_name(funnel).unwrap();
+    for address in addresses {
+        let udpsrc = gst::ElementFactory::make("udpsrc")
+            .property("address", address.to_string())
+            .property("port", port as i32)
+            .build()?;
+        pipeline.add(&udpsrc)?;
+        udpsrc.link(&funnel)?;
+        // the pipeline may already be running, e.g. the receiver's one for a relay
+        udpsrc.sync_state_with_parent()?;
+    }
+    Ok(())
+}
 
 pub enum Streaming {
     Client(client::StreamingClient),
-    Server(server::StreamingServer),
+    /// Boxed, the server holds far more than the client's shared state
+    Server(Box<server::StreamingServer>),
 }
 
 impl Streaming {
@@ -11,14 +51,17 @@ impl Streaming {
         ip: T,
         image_parser: impl FnMut(&[u8]) + Send + 'static,
         save_stream: bool,
+        config: client::StreamingClientConfig,
     ) -> Result<Self, client::StreamingClientError> {
-        client::StreamingClient::new(ip, image_parser, save_stream).map(Streaming::Client)
+        client::StreamingClient::new(ip, image_parser, save_stream, config).map(Streaming::Client)
     }
 
     pub fn new_server(
         image_parser: impl FnMut(&[u8]) + Send + 'static,
+        config: server::StreamingServerConfig,
     ) -> Result<Self, server::StreamingServerError> {
-        server::StreamingServer::new(image_parser).map(Streaming::Server)
+        server::StreamingServer::new(image_parser, config)
+            .map(|server| Streaming::Server(Box::new(server)))
     }
 
     pub fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
@@ -0,0 +1,82 @@
+//! Audio of the caster, encoded with Opus and sent as a second RTP session of
+//! the same rtpbin. The receivers' rtpbin lines it up with the video from the
+//! sender reports of both sessions, which share the caster's CNAME.
+//!
+//! The audio goes to the receiver's media port plus `AUDIO_PORT_OFFSET` and
+//! its sender reports to the port after that, the receivers' reports come back
+//! to the caster's RTCP port plus one. It's only sent over UDP: it isn't
+//! relayed, nor carried over the websocket fallback.
+//!
+//! With the default ports the receiver gets the audio on 9003 and 9004, the
+//! caster its reports on 9006 and a multicast group on 9013 and 9014, see the
+//! layout in `connection`.
+
+pub const AUDIO_PORT_OFFSET: u16 = 2;
+
+/// Payload type of the Opus packets
+pub(crate) const OPUS_PT: u32 = 111;
+
+/// Caps of the audio packets reaching the receiver, before SRTP decryption
+pub(crate) const AUDIO_RTP_CAPS: &str =
+    "application/x-srtp, media=audio, clock-rate=48000, encoding-name=OPUS, payload=111";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioSource {
+    Microphone,
+    /// What the system plays, e.g. the soundtrack of a video being presented.
+    /// Not available on macOS, where the default input is captured instead.
+    SystemOutput,
+    /// The microphone and the system output mixed together
+    Both,
+    /// A tone, to test without capturing anything
+    Test,
+}
+
+#[derive(Clone, Debug)]
+pub struct AudioConfig {
+    pub source: AudioSource,
+    /// Opus bitrate in kbit/s
+    pub bitrate: u32,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            source: AudioSource::Microphone,
+            bitrate: 64,
+        }
+    }
+}
+
+impl AudioConfig {
+    /// Branch of the caster's pipeline, from the capture to the UDP sinks of the
+    /// second session of `rtpbin`, encrypted by the same `srtpenc` as the video
+    pub(crate) fn pipeline_branch(&self) -> String {
+        let source = match self.source {
+            AudioSource::Microphone => "autoaudiosrc".to_string(),
+            AudioSource::SystemOutput => system_output().to_string(),
+            AudioSource::Both => format!(
+                "autoaudiosrc ! audioconvert ! audioresample ! audiomixer name=audiomix {} ! audioconvert ! audioresample ! audiomix. audiomix.",
+                system_output()
+            ),
+            AudioSource::Test => "audiotestsrc is-live=true wave=ticks".to_string(),
+        };
+        format!(
+            " {} ! audioconvert ! audioresample ! audio/x-raw,rate=48000,channels=2 ! opusenc bitrate={} ! rtpopuspay pt={} ! rtpbin.send_rtp_sink_1 rtpbin.send_rtp_src_1 ! srtpenc.rtp_sink_1 srtpenc.rtp_src_1 ! multiudpsink name=audiosink rtpbin.send_rtcp_src_1 ! multiudpsink name=audiortcpsink sync=false async=false funnel name=audiortcpsrc ! rtpbin.recv_rtcp_sink_1",
+            source,
+            self.bitrate * 1000,
+            OPUS_PT
+        )
+    }
+}
+
+/// Loopback capture of the default output device
+fn system_output() -> &'static str {
+    if cfg!(target_os = "windows") {
+        "wasapi2src loopback=true"
+    } else if cfg!(target_os = "linux") {
+        "pulsesrc device=@DEFAULT_MONITOR@"
+    } else {
+        "autoaudiosrc"
+    }
+}
@@ -0,0 +1,239 @@
+//! Adaptation of the encoder bitrate to the reception reports of the receivers.
+//!
+//! The bitrate is cut as soon as a receiver reports losses and raised slowly
+//! while every receiver gets the stream cleanly, always within the configured bounds.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+
+use crate::connection::message::ReceptionReport;
+
+/// How often the reports are evaluated, receivers send one every second
+pub const ADAPTATION_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Changes kept for `StreamingServer::bitrate_changes`
+const HISTORY_LEN: usize = 50;
+
+/// The bitrate isn't raised again for this long after being cut
+const INCREASE_HOLDOFF: Duration = Duration::from_secs(10);
+
+/// Bounds of the x264enc bitrate, in kbit/s
+#[derive(Clone, Debug)]
+pub struct BitrateConfig {
+    pub initial: u32,
+    pub min: u32,
+    pub max: u32,
+    /// When false the bitrate stays at `initial` whatever the receivers report
+    pub adaptive: bool,
+}
+
+impl Default for BitrateConfig {
+    fn default() -> Self {
+        Self {
+            // x264enc's own default
+            initial: 2048,
+            min: 256,
+            max: 8192,
+            adaptive: true,
+        }
+    }
+}
+
+/// A change of the encoder bitrate and what caused it
+#[derive(Clone, Debug)]
+pub struct BitrateChange {
+    pub at: DateTime<Local>,
+    /// New bitrate in kbit/s
+    pub bitrate: u32,
+    pub reason: String,
+}
+
+pub(crate) struct BitrateController {
+    config: BitrateConfig,
+    bitrate: u32,
+    history: VecDeque<BitrateChange>,
+    last_decrease: Option<Instant>,
+}
+
+impl BitrateController {
+    pub fn new(config: BitrateConfig) -> Self {
+        let bitrate = config.initial.clamp(config.min, config.max.max(config.min));
+        let mut controller = Self {
+            config,
+            bitrate,
+            history: VecDeque::new(),
+            last_decrease: None,
+        };
+        controller.record(bitrate, "initial bitrate".to_string());
+        controller
+    }
+
+    pub fn bitrate(&self) -> u32 {
+        self.bitrate
+    }
+
+    /// Oldest first
+    pub fn history(&self) -> Vec<BitrateChange> {
+        self.history.iter().cloned().collect()
+    }
+
+    /// Evaluates the reports received since the last call, with the name of the
+    /// receiver that sent them. Returns the change when the bitrate has to change.
+    pub fn update(&mut self, reports: &[(String, ReceptionReport)]) -> Option<BitrateChange> {
+        if !self.config.adaptive || reports.is_empty() {
+            return None;
+        }
+        // the stream is the same for everybody, it has to suit the worst receiver
+        let (lossy, loss) = reports
+            .iter()
+            .map(|(name, report)| (name, report.fraction_lost))
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+        let (jittery, jitter) = reports
+            .iter()
+            .map(|(name, report)| (name, report.jitter_ms))
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+
+        let (bitrate, reason) = if loss > 0.10 {
+            (
+                self.bitrate * 7 / 10,
+                format!("{:.1}% packet loss reported by {}", loss * 100.0, lossy),
+            )
+        } else if loss > 0.02 {
+            (
+                self.bitrate * 85 / 100,
+                format!("{:.1}% packet loss reported by {}", loss * 100.0, lossy),
+            )
+        } else if jitter > 50.0 {
+            (
+                self.bitrate * 9 / 10,
+                format!("{:.0} ms jitter reported by {}", jitter, jittery),
+            )
+        } else if loss < 0.01
+            && self
+                .last_decrease
+                .map_or(true, |at| at.elapsed() > INCREASE_HOLDOFF)
+        {
+            (
+                self.bitrate + (self.bitrate / 20).max(64),
+                format!("no loss reported by {} receiver(s)", reports.len()),
+            )
+        } else {
+            return None;
+        };
+
+        let bitrate = bitrate.clamp(self.config.min, self.config.max.max(self.config.min));
+        if bitrate == self.bitrate {
+            return None;
+        }
+        if bitrate < self.bitrate {
+            self.last_decrease = Some(Instant::now());
+        }
+        self.bitrate = bitrate;
+        Some(self.record(bitrate, reason))
+    }
+
+    fn record(&mut self, bitrate: u32, reason: String) -> BitrateChange {
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        let change = BitrateChange {
+            at: Local::now(),
+            bitrate,
+            reason,
+        };
+        self.history.push_back(change.clone());
+        change
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(fraction_lost: f32, jitter_ms: f32) -> Vec<(String, ReceptionReport)> {
+        vec![
+            (
+                "clean".to_string(),
+                ReceptionReport {
+                    fraction_lost: 0.0,
+                    jitter_ms: 1.0,
+                    latency_ms: 200.0,
+                    ssrc: 1,
+                },
+            ),
+            (
+                "measured".to_string(),
+                ReceptionReport {
+                    fraction_lost,
+                    jitter_ms,
+                    latency_ms: 200.0,
+                    ssrc: 1,
+                },
+            ),
+        ]
+    }
+
+    fn controller(initial: u32) -> BitrateController {
+        BitrateController::new(BitrateConfig {
+            initial,
+            min: 500,
+            max: 1000,
+            adaptive: true,
+        })
+    }
+
+    #[test]
+    fn initial_bitrate_is_within_bounds() {
+        assert_eq!(controller(100).bitrate(), 500);
+        assert_eq!(controller(5000).bitrate(), 1000);
+    }
+
+    #[test]
+    fn losses_cut_the_bitrate_down_to_the_minimum() {
+        let mut controller = controller(1000);
+        let change = controller.update(&report(0.2, 1.0)).unwrap();
+        assert_eq!(change.bitrate, 700);
+        assert!(change.reason.contains("measured"));
+        assert_eq!(controller.update(&report(0.05, 1.0)).unwrap().bitrate, 595);
+        assert_eq!(controller.update(&report(0.5, 1.0)).unwrap().bitrate, 500);
+        // already at the minimum
+        assert!(controller.update(&report(0.5, 1.0)).is_none());
+        assert_eq!(controller.history().len(), 4);
+    }
+
+    #[test]
+    fn jitter_cuts_the_bitrate() {
+        let mut controller = controller(1000);
+        assert_eq!(controller.update(&report(0.0, 80.0)).unwrap().bitrate, 900);
+    }
+
+    #[test]
+    fn clean_reports_raise_the_bitrate_up_to_the_maximum() {
+        let mut controller = controller(900);
+        assert_eq!(controller.update(&report(0.0, 1.0)).unwrap().bitrate, 964);
+        assert_eq!(controller.update(&report(0.0, 1.0)).unwrap().bitrate, 1000);
+        assert!(controller.update(&report(0.0, 1.0)).is_none());
+    }
+
+    #[test]
+    fn no_raise_right_after_a_cut() {
+        let mut controller = controller(1000);
+        controller.update(&report(0.2, 1.0)).unwrap();
+        assert!(controller.update(&report(0.0, 1.0)).is_none());
+        // as if the holdoff was over
+        controller.last_decrease = Instant::now().checked_sub(INCREASE_HOLDOFF * 2);
+        assert_eq!(controller.update(&report(0.0, 1.0)).unwrap().bitrate, 764);
+    }
+
+    #[test]
+    fn fixed_bitrate_ignores_the_reports() {
+        let mut controller = BitrateController::new(BitrateConfig {
+            adaptive: false,
+            ..Default::default()
+        });
+        assert!(controller.update(&report(0.5, 100.0)).is_none());
+        assert!(controller.update(&[]).is_none());
+    }
+}
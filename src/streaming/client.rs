@@ -1,13 +1,30 @@
 use byte_slice_cast::*;
 use std::{
     io,
+    net::SocketAddr,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex, Weak,
     },
+    thread,
+    time::{Duration, Instant},
 };
 
-use crate::connection::client::ConnectionClient;
+use super::audio::{AUDIO_PORT_OFFSET, AUDIO_RTP_CAPS, OPUS_PT};
+use super::latency::{capture_time, CAPTURE_TIME_EXTENSION};
+use super::relay::{Relay, RelayConfig};
+use super::srt::SrtConfig;
+use super::stats::{latency, resolution, rtp_sources, ClientStats, RateMeter};
+use crate::connection::client::{
+    ClientHandle, ConnectionClient, ConnectionClientConfig, DisconnectReason,
+};
+use crate::connection::clock::{now_micros, ClockOffset};
+use crate::connection::message::{ControlMessage, Hello, MediaTransport, ReceptionReport};
+use crate::connection::tls::{TlsClientConfig, TlsIdentity};
+use crate::connection::{
+    resolve, user_name, DEFAULT_MEDIA_PORT, DEFAULT_RTCP_PORT, DEFAULT_SIGNALING_PORT,
+    DEFAULT_TIMEOUT,
+};
 use gstreamer::{self as gst, element_error, glib, prelude::*};
 use gstreamer_app as gst_app;
 use thiserror::Error;
@@ -27,12 +44,693 @@ pub enum StreamingClientError {
 
     #[error("Websocket error: {0}")]
     WebsocketError(#[from] io::Error),
+
+    #[error("Could not resolve the caster address: {0}")]
+    ResolveError(io::Error),
+
+    #[error("Authentication failed: {0}")]
+    AuthenticationFailed(String),
+
+    #[error("Rejected by the caster: {0}")]
+    Rejected(String),
+
+    #[error("Caster error: {0}")]
+    CasterError(String),
+
+    #[error("Disconnected: {0}")]
+    Disconnected(String),
+
+    #[error("Timed out: {0}")]
+    Timeout(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct StreamingClientConfig {
+    /// Port of the caster's signaling websocket
+    pub port: u16,
+    /// Local UDP port the RTP stream is received on, announced to the caster
+    pub media_port: u16,
+    /// Name shown to the caster
+    pub name: String,
+    /// Passphrase required by casters started with one
+    pub passphrase: Option<String>,
+    /// The caster is considered gone when the signaling is silent for longer than this
+    pub timeout: Duration,
+    /// The stream is considered stalled when no video arrives for longer than this
+    pub media_timeout: Duration,
+    /// When set and no UDP packet arrives within this time after being admitted,
+    /// the media is requested over the signaling websocket
+    pub websocket_fallback: Option<Duration>,
+    /// Size of the jitter buffer, how long packets can be late, reordered or retransmitted
+    pub latency: Duration,
+    /// Asks the caster to resend the lost packets (NACK)
+    pub retransmission: bool,
+    /// Fraction of the UDP packets dropped on purpose (netsim), to test the loss recovery
+    pub simulated_loss: f32,
+    /// When set the connection is retried instead of stopping the reception
+    pub reconnect: Option<ReconnectPolicy>,
+    /// Required by casters started with TLS, their certificate is pinned on first use
+    pub tls: Option<TlsClientConfig>,
+    /// When set the stream is forwarded to the receivers connecting to this one
+    pub relay: Option<RelayConfig>,
+    /// When set the stream is pulled over SRT instead, from an ingest point or a
+    /// caster's SRT output, and the caster's signaling isn't used at all
+    pub srt: Option<SrtConfig>,
+    /// Plays the caster's audio, when it sends some, and records it with the video.
+    /// It's only received over RTP, not over SRT nor the websocket fallback
+    pub audio: bool,
+}
+
+impl Default for StreamingClientConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_SIGNALING_PORT,
+            media_port: DEFAULT_MEDIA_PORT,
+            name: user_name(),
+            passphrase: None,
+            timeout: DEFAULT_TIMEOUT,
+            media_timeout: Duration::from_secs(5),
+            websocket_fallback: Some(Duration::from_secs(3)),
+            latency: Duration::from_millis(200),
+            retransmission: true,
+            simulated_loss: 0.0,
+            reconnect: None,
+            tls: None,
+            relay: None,
+            srt: None,
+            audio: true,
+        }
+    }
+}
+
+/// Opt-in policy used when the connection to the caster is lost
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    pub max_attempts: u32,
+    /// Delay before the first attempt, doubled after every failure
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(8),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    Connected,
+    /// The connection was lost and the attempt-th reconnection is in progress
+    Reconnecting {
+        attempt: u32,
+    },
+    /// The connection is gone for good and the pipeline has been stopped
+    Disconnected,
+}
+
+/// State shared between the StreamingClient and the connection callbacks
+struct Shared {
+    pipeline: gst::Pipeline,
+    ip: String,
+    config: StreamingClientConfig,
+    connection_client: Mutex<Option<ConnectionClient>>,
+    state: Mutex<ConnectionState>,
+    caster_paused: AtomicBool,
+    caster_blanked: AtomicBool,
+    awaiting_approval: AtomicBool,
+    last_media: Mutex<Instant>,
+    /// Feeds the packets received as `ControlMessage::Media` into the pipeline, None over SRT
+    media_src: Option<gst_app::AppSrc>,
+    transport: Mutex<MediaTransport>,
+    /// When the caster admitted this receiver, the UDP fallback timeout starts from there
+    admitted_at: Mutex<Option<Instant>>,
+    udp_received: AtomicBool,
+    /// Group udpsrc joined, when the caster multicasts
+    multicast: Mutex<Option<SocketAddr>>,
+    /// Answer to srtpdec's key requests, None until the caster sent the stream info
+    srtp_caps: Mutex<Option<gst::Caps>>,
+    /// Jitter buffer of the caster's stream, its statistics are sent back as reception reports
+    jitterbuffer: Mutex<Option<gst::Element>>,
+    /// Frames out of the decoder
+    decoded: Arc<RateMeter>,
+    /// Packets from either udpsrc or the websocket
+    received: Arc<RateMeter>,
+    /// Capture time of the last decoded frame in the caster's clock and
+    /// decoding time in the local one, in microseconds
+    last_frame: Mutex<Option<(u64, u64)>>,
+    relay: Mutex<Option<Relay>>,
+    /// The relay was stopped, or never started, because the caster asks for approval
+    relay_refused: AtomicBool,
+    error: Mutex<Option<StreamingClientError>>,
+}
+
+impl Shared {
+    /// Opens a new signaling connection whose callbacks update this state
+    fn connect(self: &Arc<Self>) -> io::Result<ConnectionClient> {
+        let shared = Arc::downgrade(self);
+        let shared_clone = shared.clone();
+        ConnectionClient::new(
+            &self.ip,
+            Hello::new(self.config.name.clone(), self.config.media_port),
+            ConnectionClientConfig {
+                port: self.config.port,
+                passphrase: self.config.passphrase.clone(),
+                timeout: self.config.timeout,
+                tls: self.config.tls.clone(),
+            },
+            move |handle, message| {
+                if let Some(shared) = shared.upgrade() {
+                    shared.on_message(handle, message);
+                }
+            },
+            move |reason| {
+                if let Some(shared) = shared_clone.upgrade() {
+                    shared.on_disconnect(reason);
+                }
+            },
+        )
+    }
+
+    fn on_message(self: &Arc<Self>, handle: &ClientHandle, message: ControlMessage) {
+        match message {
+            ControlMessage::Media(data) => {
+                if let Some(media_src) = &self.media_src {
+                    let _ = media_src.push_buffer(gst::Buffer::from_slice(data));
+                }
+            }
+            ControlMessage::StreamInfo {
+                paused,
+                blanked,
+                srtp_key,
+                multicast,
+                rtcp_port,
+                audio,
+                ..
+            } => {
+                self.pipeline
+                    .by_name("rtcpsink")
+                    .unwrap()
+                    .set_property("port", rtcp_port as i32);
+                if let Some(group) = multicast {
+                    self.join_multicast(group);
+                }
+                if audio && self.config.audio {
+                    if let Err(e) = self.add_audio() {
+                        println!("Failed to receive the audio: {}", e);
+                        self.end_audio_recording();
+                    }
+                } else {
+                    self.end_audio_recording();
+                }
+                if let Some(audiortcpsink) = self.pipeline.by_name("audiortcpsink") {
+                    audiortcpsink.set_property("port", rtcp_port.wrapping_add(1) as i32);
+                }
+                let caps = srtp_caps(srtp_key.as_deref());
+                let mut current = self.srtp_caps.lock().unwrap();
+                if current.as_ref() != Some(&caps) {
+                    *current = Some(caps);
+                    drop(current);
+                    // a new session (e.g. after reconnecting to a restarted caster) has a new key
+                    for name in ["srtpdec", "audiosrtpdec"] {
+                        if let Some(srtpdec) = self.pipeline.by_name(name) {
+                            srtpdec.emit_by_name::<()>("clear-keys", &[]);
+                        }
+                    }
+                }
+                self.caster_paused.store(paused, Ordering::Relaxed);
+                self.caster_blanked.store(blanked, Ordering::Relaxed);
+                self.awaiting_approval.store(false, Ordering::Relaxed);
+                *self.last_media.lock().unwrap() = Instant::now();
+                *self.admitted_at.lock().unwrap() = Some(Instant::now());
+                // after reconnecting, UDP is most likely still blocked
+                if *self.transport.lock().unwrap() == MediaTransport::Websocket {
+                    handle.send(&ControlMessage::SetMediaTransport(
+                        MediaTransport::Websocket,
+                    ));
+                    self.end_audio_recording();
+                }
+            }
+            ControlMessage::AwaitingApproval => {
+                self.awaiting_approval.store(true, Ordering::Relaxed);
+                // the relay would let anyone in, its receivers are told it ended. Under
+                // the relay's lock, so that a relay still being created isn't started.
+                let mut relay = self.relay.lock().unwrap();
+                if self.config.relay.is_some() && !self.relay_refused.swap(true, Ordering::Relaxed)
+                {
+                    println!("The caster asks for approval, the relay is stopped");
+                }
+                relay.take();
+            }
+            ControlMessage::Rejected(reason) => {
+                *self.error.lock().unwrap() = Some(StreamingClientError::Rejected(reason));
+            }
+            ControlMessage::Paused => self.caster_paused.store(true, Ordering::Relaxed),
+            ControlMessage::Resumed => {
+                self.caster_paused.store(false, Ordering::Relaxed);
+                *self.last_media.lock().unwrap() = Instant::now();
+            }
+            ControlMessage::Blanked => self.caster_blanked.store(true, Ordering::Relaxed),
+            ControlMessage::Restored => self.caster_blanked.store(false, Ordering::Relaxed),
+            ControlMessage::AuthRejected(reason) => {
+                let reason = if self.config.passphrase.is_some() {
+                    reason
+                } else {
+                    "the caster requires a passphrase".to_string()
+                };
+                *self.error.lock().unwrap() =
+                    Some(StreamingClientError::AuthenticationFailed(reason));
+            }
+            ControlMessage::Error(e) => {
+                *self.error.lock().unwrap() = Some(StreamingClientError::CasterError(e));
+            }
+            _ => {}
+        }
+        if let Some(relay) = &*self.relay.lock().unwrap() {
+            relay.mirror(
+                self.caster_paused.load(Ordering::Relaxed),
+                self.caster_blanked.load(Ordering::Relaxed),
+            );
+        }
+    }
+
+    /// Moves udpsrc from the local media port to the caster's multicast group
+    fn join_multicast(&self, group: SocketAddr) {
+        let mut multicast = self.multicast.lock().unwrap();
+        if *multicast == Some(group) {
+            return;
+        }
+        *multicast = Some(group);
+        println!("Joining multicast group {}", group);
+        // the caster's sender reports are sent to the next port of the group
+        let audio_port = group.port().wrapping_add(AUDIO_PORT_OFFSET);
+        for (name, port) in [
+            ("udpsrc", group.port()),
+            ("rtcpsrc", group.port().wrapping_add(1)),
+            ("audiosrc", audio_port),
+            ("audiortcpsrc", audio_port.wrapping_add(1)),
+        ] {
+            let Some(udpsrc) = self.pipeline.by_name(name) else {
+                continue;
+            };
+            let _ = udpsrc.set_state(gst::State::Null);
+            // `address` replaced the `multicast-group` property of older releases
+            udpsrc.set_property("address", group.ip().to_string());
+            udpsrc.set_property("port", port as i32);
+            udpsrc.set_property("auto-multicast", true);
+            let _ = udpsrc.sync_state_with_parent();
+        }
+        let mut transport = self.transport.lock().unwrap();
+        if *transport == MediaTransport::Udp {
+            *transport = MediaTransport::Multicast;
+        }
+    }
+
+    fn on_disconnect(self: &Arc<Self>, reason: DisconnectReason) {
+        // errors reported by the caster (rejection, wrong passphrase...) are final
+        let refused = self.error.lock().unwrap().is_some();
+        match &self.config.reconnect {
+            Some(policy) if !refused => {
+                println!("Connection lost ({:?}), reconnecting", reason);
+                *self.state.lock().unwrap() = ConnectionState::Reconnecting { attempt: 1 };
+                reconnect(Arc::downgrade(self), policy.clone());
+            }
+            _ => {
+                self.set_error(match reason {
+                    DisconnectReason::Goodbye => StreamingClientError::Disconnected(
+                        "the caster ended the transmission".to_string(),
+                    ),
+                    DisconnectReason::Closed => StreamingClientError::Disconnected(
+                        "the connection to the caster was closed".to_string(),
+                    ),
+                    DisconnectReason::Timeout => {
+                        StreamingClientError::Timeout("the caster stopped responding".to_string())
+                    }
+                });
+                self.stop_pipeline();
+            }
+        }
+    }
+
+    /// Keeps the first error, which is the one that explains the disconnection
+    fn set_error(&self, e: StreamingClientError) {
+        let mut error = self.error.lock().unwrap();
+        if error.is_none() {
+            *error = Some(e);
+        }
+    }
+
+    /// Receives and plays the audio once the caster says it sends some, in a
+    /// second session of rtpbin played in sync with the video from the
+    /// caster's sender reports. Until then no port is bound for it.
+    fn add_audio(self: &Arc<Self>) -> Result<(), glib::BoolError> {
+        if self.pipeline.by_name("audiodepay").is_some() {
+            return Ok(());
+        }
+        // in the caster's group, when it multicasts
+        let (address, port) = match *self.multicast.lock().unwrap() {
+            Some(group) => (group.ip().to_string(), group.port()),
+            None => (
+                self.pipeline
+                    .by_name("udpsrc")
+                    .unwrap()
+                    .property::<String>("address"),
+                self.config.media_port,
+            ),
+        };
+        let port = port.wrapping_add(AUDIO_PORT_OFFSET);
+        let caster = self
+            .pipeline
+            .by_name("rtcpsink")
+            .unwrap()
+            .property::<String>("host");
+
+        let udpsrc = gst::ElementFactory::make("udpsrc")
+            .name("audiosrc")
+            .property("address", &address)
+            .property("port", port as i32)
+            .property("caps", AUDIO_RTP_CAPS.parse::<gst::Caps>()?)
+            .build()?;
+        let srtpdec = gst::ElementFactory::make("srtpdec")
+            .name("audiosrtpdec")
+            .build()?;
+        let rtcpsrc = gst::ElementFactory::make("udpsrc")
+            .name("audiortcpsrc")
+            .property("address", &address)
+            .property("port", port.wrapping_add(1) as i32)
+            .build()?;
+        let rtcpsink = gst::ElementFactory::make("udpsink")
+            .name("audiortcpsink")
+            .property("host", caster)
+            .property("sync", false)
+            .property("async", false)
+            .build()?;
+        let playback = [
+            gst::ElementFactory::make("rtpopusdepay")
+                .name("audiodepay")
+                .build()?,
+            gst::ElementFactory::make("tee").name("a").build()?,
+            gst::ElementFactory::make("queue").build()?,
+            gst::ElementFactory::make("opusdec").build()?,
+            gst::ElementFactory::make("audioconvert").build()?,
+            gst::ElementFactory::make("audioresample").build()?,
+            gst::ElementFactory::make("autoaudiosink").build()?,
+        ];
+
+        self.pipeline
+            .add_many([&udpsrc, &srtpdec, &rtcpsrc, &rtcpsink])?;
+        self.pipeline.add_many(&playback)?;
+        let rtpbin = self.pipeline.by_name("rtpbin").unwrap();
+        udpsrc.link_pads(None, &srtpdec, Some("rtp_sink"))?;
+        srtpdec.link_pads(Some("rtp_src"), &rtpbin, Some("recv_rtp_sink_1"))?;
+        rtcpsrc.link_pads(None, &rtpbin, Some("recv_rtcp_sink_1"))?;
+        rtpbin.link_pads(Some("send_rtcp_src_1"), &rtcpsink, None)?;
+        // rtpbin links the depayloader when the caster's audio stream shows up
+        gst::Element::link_many(&playback)?;
+        if let Some(record) = self.pipeline.by_name("audiorecord") {
+            playback[1].link(&record)?;
+        }
+        self.answer_key_requests(&srtpdec);
+
+        for element in [&udpsrc, &srtpdec, &rtcpsrc, &rtcpsink]
+            .into_iter()
+            .chain(&playback)
+        {
+            element.sync_state_with_parent()?;
+        }
+        Ok(())
+    }
+
+    /// Packets arriving before the stream info are dropped, srtpdec asks again for the next ones
+    fn answer_key_requests(self: &Arc<Self>, srtpdec: &gst::Element) {
+        let shared = Arc::downgrade(self);
+        srtpdec.connect("request-key", false, move |_| {
+            let caps = shared.upgrade()?.srtp_caps.lock().unwrap().clone();
+            caps.map(|caps| caps.to_value())
+        });
+    }
+
+    /// Lets the recording go on without audio, the muxer would otherwise wait for it
+    /// (the caster doesn't capture any, or the media comes over the websocket)
+    fn end_audio_recording(&self) {
+        if let Some(queue) = self.pipeline.by_name("audiorecord") {
+            queue
+                .static_pad("sink")
+                .unwrap()
+                .send_event(gst::event::Eos::new());
+        }
+    }
+
+    /// Stops the pipeline once the recording, if any, is finalized. Only the first call has effect
+    fn stop_pipeline(&self) {
+        let mut state = self.state.lock().unwrap();
+        if *state == ConnectionState::Disconnected {
+            return;
+        }
+        *state = ConnectionState::Disconnected;
+        drop(state);
+        // the relay's receivers are told the transmission ended
+        self.relay.lock().unwrap().take();
+        // without a recording there is nothing to finalize
+        if self.pipeline.by_name("mux").is_none() {
+            let _ = self.pipeline.set_state(gst::State::Null);
+            return;
+        }
+        // it's finalized in the background, the client is dropped from the UI thread
+        let pipeline = self.pipeline.clone();
+        thread::spawn(move || {
+            pipeline.send_event(gst::event::Eos::new());
+            // a branch that never gets data (e.g. the audio of a caster without any)
+            // would hold the EOS back, the recording is then left unfinished
+            let finalized = pipeline.bus().unwrap().timed_pop_filtered(
+                EOS_TIMEOUT,
+                &[gst::MessageType::Eos, gst::MessageType::Error],
+            );
+            if !matches!(
+                finalized.as_ref().map(|message| message.view()),
+                Some(gst::MessageView::Eos(_))
+            ) {
+                println!("The pipeline didn't drain, the recording may be incomplete");
+            }
+            let _ = pipeline.set_state(gst::State::Null);
+        });
+    }
+}
+
+/// How long the recording is given to be finalized when the reception stops
+const EOS_TIMEOUT: gst::ClockTime = gst::ClockTime::from_seconds(3);
+
+/// Parameters srtpdec needs to decrypt the media, all null when the caster doesn't encrypt it.
+/// RTCP is never encrypted, it doesn't go through srtpenc and srtpdec.
+fn srtp_caps(key: Option<&[u8]>) -> gst::Caps {
+    let builder = gst::Caps::builder("application/x-srtp");
+    match key {
+        Some(key) => builder
+            .field("srtp-key", gst::Buffer::from_slice(key.to_vec()))
+            .field("srtp-cipher", "aes-128-icm")
+            .field("srtp-auth", "hmac-sha1-80"),
+        None => builder
+            .field("srtp-cipher", "null")
+            .field("srtp-auth", "null"),
+    }
+    .field("srtcp-cipher", "null")
+    .field("srtcp-auth", "null")
+    .build()
+}
+
+/// Asks the caster to send the media over the websocket when no UDP arrives in time
+fn watch_udp(shared: Weak<Shared>, fallback: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(250));
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        if *shared.state.lock().unwrap() == ConnectionState::Disconnected
+            || shared.udp_received.load(Ordering::Relaxed)
+        {
+            return;
+        }
+        let admitted_at = *shared.admitted_at.lock().unwrap();
+        let waiting = match admitted_at {
+            Some(admitted_at) => admitted_at.elapsed(),
+            None => continue,
+        };
+        if waiting < fallback || shared.caster_paused.load(Ordering::Relaxed) {
+            continue;
+        }
+        let connection_client = shared.connection_client.lock().unwrap();
+        if let Some(connection_client) = &*connection_client {
+            println!("No UDP media received, switching to the websocket");
+            *shared.transport.lock().unwrap() = MediaTransport::Websocket;
+            *shared.last_media.lock().unwrap() = Instant::now();
+            connection_client.send(&ControlMessage::SetMediaTransport(
+                MediaTransport::Websocket,
+            ));
+            shared.end_audio_recording();
+            return;
+        }
+    });
+}
+
+/// Sends the loss and jitter measured by the jitter buffer to the caster every second
+fn report_reception(shared: Weak<Shared>) {
+    thread::spawn(move || {
+        // counters of the jitter buffer at the previous report
+        let mut previous = (0, 0);
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            let shared = match shared.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+            if *shared.state.lock().unwrap() == ConnectionState::Disconnected {
+                return;
+            }
+            let stats = match &*shared.jitterbuffer.lock().unwrap() {
+                Some(jitterbuffer) => jitterbuffer.property::<gst::Structure>("stats"),
+                None => continue,
+            };
+            let pushed = stats.get::<u64>("num-pushed").unwrap_or(0);
+            let lost = stats.get::<u64>("num-lost").unwrap_or(0);
+            // the counters start over when a new jitter buffer replaces the old one
+            let expected = pushed.saturating_sub(previous.0) + lost.saturating_sub(previous.1);
+            let report = ReceptionReport {
+                fraction_lost: lost.saturating_sub(previous.1) as f32 / expected.max(1) as f32,
+                jitter_ms: stats.get::<u64>("avg-jitter").unwrap_or(0) as f32 / 1_000_000.0,
+                latency_ms: latency(&shared.pipeline).map_or(0.0, |l| l.as_secs_f32() * 1000.0),
+                ssrc: shared
+                    .pipeline
+                    .by_name("rtpbin")
+                    .unwrap()
+                    .emit_by_name::<glib::Object>("get-internal-session", &[&0u32])
+                    .property::<u32>("internal-ssrc"),
+            };
+            previous = (pushed, lost);
+            // nothing to report while paused, nor when TCP carries the media
+            if expected == 0 || *shared.transport.lock().unwrap() == MediaTransport::Websocket {
+                continue;
+            }
+            let connection_client = shared.connection_client.lock().unwrap();
+            if let Some(connection_client) = &*connection_client {
+                connection_client.send(&ControlMessage::ReceptionReport(report));
+            }
+        }
+    });
+}
+
+/// Tries to connect again with exponential backoff while the pipeline keeps running
+fn reconnect(shared: Weak<Shared>, policy: ReconnectPolicy) {
+    thread::spawn(move || {
+        let mut delay = policy.initial_delay;
+        for attempt in 1..=policy.max_attempts {
+            thread::sleep(delay);
+            let shared = match shared.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+            {
+                let mut state = shared.state.lock().unwrap();
+                if *state == ConnectionState::Disconnected {
+                    return;
+                }
+                *state = ConnectionState::Reconnecting { attempt };
+            }
+            match shared.connect() {
+                Ok(connection_client) => {
+                    println!("Reconnected after {} attempt(s)", attempt);
+                    *shared.last_media.lock().unwrap() = Instant::now();
+                    *shared.connection_client.lock().unwrap() = Some(connection_client);
+                    let mut state = shared.state.lock().unwrap();
+                    if let ConnectionState::Reconnecting { .. } = *state {
+                        *state = ConnectionState::Connected;
+                    }
+                    return;
+                }
+                Err(e) => println!("Reconnection attempt {} failed: {}", attempt, e),
+            }
+            delay = (delay * 2).min(policy.max_delay);
+        }
+        if let Some(shared) = shared.upgrade() {
+            shared.set_error(StreamingClientError::Disconnected(format!(
+                "could not reconnect to the caster after {} attempts",
+                policy.max_attempts
+            )));
+            shared.stop_pipeline();
+        }
+    });
+}
+
+/// Retransmission, FEC and payload mapping of the RTP session with the caster
+fn configure_rtp(pipeline: &gst::Pipeline, config: &StreamingClientConfig) {
+    // same mapping as the caster: lost packets of payload type 96 come back as 97
+    pipeline.by_name("rtx").unwrap().set_property(
+        "payload-type-map",
+        gst::Structure::builder("application/x-rtp-pt-map")
+            .field("96", 97u32)
+            .build(),
+    );
+    let rtpbin = pipeline.by_name("rtpbin").unwrap();
+    let fec = pipeline.by_name("fec").unwrap();
+    // the FEC decoder recovers packets from the ones rtpbin keeps in its storage
+    let storage = rtpbin.emit_by_name::<glib::Object>("get-internal-storage", &[&0u32]);
+    storage.set_property("size-time", config.latency.as_nanos() as u64);
+    fec.set_property("storage", storage);
+    rtpbin.connect("request-pt-map", false, |args| {
+        let pt = args[2].get::<u32>().ok()?;
+        if pt == OPUS_PT {
+            let caps = gst::Caps::builder("application/x-rtp")
+                .field("media", "audio")
+                .field("clock-rate", 48000i32)
+                .field("encoding-name", "OPUS");
+            return Some(caps.build().to_value());
+        }
+        let caps = gst::Caps::builder("application/x-rtp")
+            .field("media", "video")
+            .field("clock-rate", 90000i32);
+        let caps = match pt {
+            // the depayloader reads the capture time of the frames
+            96 => caps
+                .field("encoding-name", "H264")
+                .field("extmap-1", CAPTURE_TIME_EXTENSION),
+            122 => caps.field("encoding-name", "ULPFEC"),
+            _ => return None,
+        };
+        Some(caps.build().to_value())
+    });
+    let pipeline = pipeline.downgrade();
+    // a new pad shows up for every stream, e.g. after the caster restarted,
+    // the ones of the second session carry the audio
+    rtpbin.connect_pad_added(move |_, pad| {
+        let name = pad.name();
+        let sink = if name.starts_with("recv_rtp_src_0_") {
+            fec.static_pad("sink").unwrap()
+        } else if name.starts_with("recv_rtp_src_1_") {
+            match pipeline
+                .upgrade()
+                .and_then(|pipeline| pipeline.by_name("audiodepay"))
+            {
+                Some(audiodepay) => audiodepay.static_pad("sink").unwrap(),
+                None => return,
+            }
+        } else {
+            return;
+        };
+        if let Some(peer) = sink.peer() {
+            let _ = peer.unlink(&sink);
+        }
+        if let Err(e) = pad.link(&sink) {
+            println!("Failed to link the received stream: {:?}", e);
+        }
+    });
 }
 
 pub struct StreamingClient {
-    pipeline: Arc<gst::Pipeline>,
-    _connection_client: ConnectionClient,
-    connected: Arc<AtomicBool>,
+    shared: Arc<Shared>,
 }
 
 impl StreamingClient {
@@ -40,18 +738,61 @@ impl StreamingClient {
         ip: T,
         mut image_parser: impl FnMut(&[u8]) + Send + 'static,
         save_stream: bool,
+        config: StreamingClientConfig,
     ) -> Result<Self, StreamingClientError> {
         gst::init()?;
 
-        let mut pipeline_string = "udpsrc port=9001 !
-        application/x-rtp, media=video, clock-rate=90000, encoding-name=H264, payload=96 ! rtph264depay ! tee name=t ! queue ! decodebin !
-        videoconvert ! jpegenc ! appsink name=s max-buffers=1 caps=image/jpeg".to_string();
+        let mut pipeline_string = match &config.srt {
+            // MPEG-TS, SRT itself recovers the lost packets
+            Some(_) => "srtsrc name=srtsrc ! tsdemux ! h264parse ! tee name=t ! queue ! decodebin ! videoconvert ! jpegenc ! appsink name=s max-buffers=1 caps=image/jpeg".to_string(),
+            None => {
+                // the caster sends the media to the address family used for the signaling
+                let caster =
+                    resolve(ip.as_ref(), config.port).map_err(StreamingClientError::ResolveError)?;
+                let address = if caster.is_ipv6() { "::" } else { "0.0.0.0" };
+
+                // the media comes either from udpsrc or, when UDP is blocked, from the websocket through appsrc
+                let caps =
+                    "application/x-srtp, media=video, clock-rate=90000, encoding-name=H264, payload=96";
+                // rtpbin's jitter buffer reorders the packets and asks for the lost ones, RTCP
+                // goes back to the caster (its port is in the stream info) and comes in on the next port
+                let pipeline_string = format!(
+                    "udpsrc name=udpsrc address={address} port={} ! {caps} ! netsim drop-probability={} ! funnel name=f !
+                srtpdec name=srtpdec ! rtprtxreceive name=rtx ! rtpbin.recv_rtp_sink_0
+                rtpbin name=rtpbin rtp-profile=avpf ignore-pt=true latency={} do-retransmission={}
+                rtpulpfecdec name=fec pt=122 ! rtph264depay ! tee name=t ! queue ! decodebin !
+                videoconvert ! jpegenc ! appsink name=s max-buffers=1 caps=image/jpeg
+                udpsrc name=rtcpsrc address={address} port={} ! rtpbin.recv_rtcp_sink_0
+                rtpbin.send_rtcp_src_0 ! udpsink name=rtcpsink host={} port={} sync=false async=false
+                appsrc name=wssrc is-live=true do-timestamp=true format=time ! {caps} ! f.",
+                    config.media_port,
+                    config.simulated_loss,
+                    config.latency.as_millis(),
+                    config.retransmission,
+                    config.media_port.wrapping_add(1),
+                    caster.ip(),
+                    DEFAULT_RTCP_PORT,
+                );
+                // the audio branch is added when the stream info says the caster sends some
+                pipeline_string
+            }
+        };
+
+        // the depayloaded stream is payloaded again for the relay's receivers
+        if config.relay.is_some() {
+            pipeline_string.push_str(Relay::pipeline_branch());
+        }
 
+        let audio = config.audio && config.srt.is_none();
         if save_stream {
             pipeline_string.push_str(&format!(
-                " t. ! queue ! h264parse ! mp4mux ! filesink location=./stream{}.mp4",
+                " t. ! queue ! h264parse ! mp4mux name=mux ! filesink location=./stream{}.mp4",
                 Local::now().format("%Y%m%d_%H%M%S")
             ));
+            // the muxer's pads can't be requested once it started, the audio is linked to the queue later
+            if audio {
+                pipeline_string.push_str(" queue name=audiorecord ! opusparse ! mux.");
+            }
         }
 
         let pipeline = gst::parse::launch(&pipeline_string)?
@@ -60,20 +801,141 @@ impl StreamingClient {
 
         let sink: gst_app::AppSink = pipeline.by_name("s").unwrap().dynamic_cast().unwrap();
 
-        let pipeline = Arc::new(pipeline);
-        let connected = Arc::new(AtomicBool::new(true));
+        if config.srt.is_none() {
+            configure_rtp(&pipeline, &config);
+        }
+        let media_src = pipeline
+            .by_name("wssrc")
+            .map(|media_src| media_src.dynamic_cast().unwrap());
+
+        let shared = Arc::new(Shared {
+            pipeline,
+            ip: ip.as_ref().to_string(),
+            config,
+            connection_client: Mutex::new(None),
+            state: Mutex::new(ConnectionState::Connected),
+            caster_paused: AtomicBool::new(false),
+            caster_blanked: AtomicBool::new(false),
+            awaiting_approval: AtomicBool::new(false),
+            last_media: Mutex::new(Instant::now()),
+            media_src,
+            transport: Mutex::new(MediaTransport::Udp),
+            admitted_at: Mutex::new(None),
+            udp_received: AtomicBool::new(false),
+            multicast: Mutex::new(None),
+            srtp_caps: Mutex::new(None),
+            jitterbuffer: Mutex::new(None),
+            decoded: Arc::new(RateMeter::new()),
+            received: Arc::new(RateMeter::new()),
+            last_frame: Mutex::new(None),
+            relay: Mutex::new(None),
+            relay_refused: AtomicBool::new(false),
+            error: Mutex::new(None),
+        });
+
+        // where the media enters the pipeline, from any transport
+        let media_pad = shared
+            .pipeline
+            .by_name("f")
+            .or_else(|| shared.pipeline.by_name("srtsrc"))
+            .unwrap()
+            .static_pad("src")
+            .unwrap();
+
+        // media watchdog, the caster may stop sending while the websocket is still open
+        let shared_clone = Arc::downgrade(&shared);
+        media_pad.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+            if let Some(shared) = shared_clone.upgrade() {
+                *shared.last_media.lock().unwrap() = Instant::now();
+            }
+            gst::PadProbeReturn::Ok
+        });
 
-        let pipeline_clone = pipeline.clone();
-        let connected_clone = connected.clone();
-        let connection_client = ConnectionClient::new(ip, move || {
-            pipeline_clone.send_event(gst::event::Eos::new());
-            pipeline_clone
-                .bus()
+        shared.received.watch(&media_pad);
+        shared.decoded.watch(&sink.static_pad("sink").unwrap());
+        let shared_clone = Arc::downgrade(&shared);
+        sink.static_pad("sink")
+            .unwrap()
+            .add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                let shared = match shared_clone.upgrade() {
+                    Some(shared) => shared,
+                    None => return gst::PadProbeReturn::Remove,
+                };
+                if let Some(captured) = info.buffer().and_then(|buffer| capture_time(buffer)) {
+                    *shared.last_frame.lock().unwrap() = Some((captured, now_micros()));
+                }
+                gst::PadProbeReturn::Ok
+            });
+
+        if let Some(srt_config) = &shared.config.srt {
+            srt_config.apply(&shared.pipeline.by_name("srtsrc").unwrap())?;
+        } else {
+            let shared_clone = Arc::downgrade(&shared);
+            shared
+                .pipeline
+                .by_name("udpsrc")
+                .unwrap()
+                .static_pad("src")
                 .unwrap()
-                .timed_pop_filtered(gst::ClockTime::NONE, &[gst::MessageType::Eos]);
-            let _ = pipeline_clone.set_state(gst::State::Null);
-            connected_clone.store(false, Ordering::Relaxed);
-        })?;
+                .add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+                    if let Some(shared) = shared_clone.upgrade() {
+                        shared.udp_received.store(true, Ordering::Relaxed);
+                    }
+                    gst::PadProbeReturn::Remove
+                });
+            if let Some(fallback) = shared.config.websocket_fallback {
+                watch_udp(Arc::downgrade(&shared), fallback);
+            }
+
+            // one per stream, a restarted caster gets a new one
+            let shared_clone = Arc::downgrade(&shared);
+            shared.pipeline.by_name("rtpbin").unwrap().connect(
+                "new-jitterbuffer",
+                false,
+                move |args| {
+                    // the reports are about the video
+                    if args[2].get::<u32>().ok()? != 0 {
+                        return None;
+                    }
+                    let jitterbuffer = args[1].get::<gst::Element>().ok()?;
+                    *shared_clone.upgrade()?.jitterbuffer.lock().unwrap() = Some(jitterbuffer);
+                    None
+                },
+            );
+            report_reception(Arc::downgrade(&shared));
+
+            shared.answer_key_requests(&shared.pipeline.by_name("srtpdec").unwrap());
+
+            let connection_client = shared.connect()?;
+            *shared.connection_client.lock().unwrap() = Some(connection_client);
+        }
+
+        if let Some(mut config) = shared.config.relay.clone() {
+            // the relay asks for the same passphrase and encrypts its signaling
+            // when the caster does, a caster asking for approval stops it
+            if config.passphrase.is_none() {
+                config.passphrase = shared.config.passphrase.clone();
+            }
+            if config.tls.is_none() && shared.config.tls.is_some() {
+                config.tls = Some(TlsIdentity::self_signed());
+            }
+            let shared_clone = Arc::downgrade(&shared);
+            let relay = Relay::new(config, &shared.pipeline, move || {
+                let shared = match shared_clone.upgrade() {
+                    Some(shared) => shared,
+                    None => return,
+                };
+                let connection_client = shared.connection_client.lock().unwrap();
+                if let Some(connection_client) = &*connection_client {
+                    connection_client.send(&ControlMessage::RequestKeyframe);
+                }
+            })?;
+            // the caster may have asked for approval while the relay was created
+            let mut current = shared.relay.lock().unwrap();
+            if !shared.relay_refused.load(Ordering::Relaxed) {
+                *current = Some(relay);
+            }
+        }
 
         sink.set_callbacks(
             gst_app::AppSinkCallbacks::builder()
@@ -116,31 +978,149 @@ impl StreamingClient {
                 .build(),
         );
 
-        Ok(Self {
-            pipeline,
-            _connection_client: connection_client,
-            connected,
-        })
+        Ok(Self { shared })
     }
 
     pub fn start(&self) -> Result<(), StreamingClientError> {
-        Ok(self.pipeline.set_state(gst::State::Playing).map(|_| ())?)
+        *self.shared.last_media.lock().unwrap() = Instant::now();
+        Ok(self
+            .shared
+            .pipeline
+            .set_state(gst::State::Playing)
+            .map(|_| ())?)
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        *self.shared.state.lock().unwrap()
     }
 
+    /// False once the caster is gone or, while it is streaming, no video arrived
+    /// for longer than the media timeout. The reason is available through `take_error`.
+    /// Also false while reconnecting, see `connection_state`.
     pub fn is_connected(&self) -> bool {
-        self.connected.load(Ordering::Relaxed)
+        if self.connection_state() != ConnectionState::Connected {
+            return false;
+        }
+        if self.is_caster_paused() || self.is_awaiting_approval() {
+            return true;
+        }
+        let silence = self.shared.last_media.lock().unwrap().elapsed();
+        if silence > self.shared.config.media_timeout {
+            self.shared.set_error(StreamingClientError::Timeout(format!(
+                "no video received for {} seconds",
+                silence.as_secs()
+            )));
+            return false;
+        }
+        true
+    }
+
+    /// Reception statistics, from the RTP session and the pipeline
+    pub fn stats(&self) -> ClientStats {
+        let pipeline = &self.shared.pipeline;
+        let (decoded_fps, _) = self.shared.decoded.rates();
+        let (_, bitrate) = self.shared.received.rates();
+        let mut stats = ClientStats {
+            decoded_fps,
+            bitrate,
+            latency: latency(pipeline),
+            glass_to_glass: self.latency(),
+            resolution: resolution(&pipeline.by_name("s").unwrap().static_pad("sink").unwrap()),
+            ..Default::default()
+        };
+        if let Some(srtsrc) = pipeline.by_name("srtsrc") {
+            // SRT's own counters, there is no RTP session
+            let srt = srtsrc.property::<gst::Structure>("stats");
+            stats.packets_received = srt.get::<i64>("packets-received").unwrap_or(0).max(0) as u64;
+            stats.packets_lost = srt.get::<i32>("packets-received-lost").unwrap_or(0).max(0) as u64;
+        }
+        // the caster's stream, the internal source is this receiver's own RTCP
+        let sources = pipeline
+            .by_name("rtpbin")
+            .map(|rtpbin| rtp_sources(&rtpbin))
+            .unwrap_or_default();
+        for source in sources.iter().filter(|source| {
+            !source.get::<bool>("internal").unwrap_or(true)
+                && source.get::<bool>("is-sender").unwrap_or(false)
+        }) {
+            stats.packets_received += source.get::<u64>("packets-received").unwrap_or(0);
+            // negative when duplicates outnumber the losses
+            stats.packets_lost += source.get::<i32>("packets-lost").unwrap_or(0).max(0) as u64;
+            let clock_rate = source.get::<i32>("clock-rate").unwrap_or(90000).max(1);
+            let jitter =
+                source.get::<u32>("jitter").unwrap_or(0) as f32 * 1000.0 / clock_rate as f32;
+            stats.jitter_ms = stats.jitter_ms.max(jitter);
+        }
+        let expected = stats.packets_received + stats.packets_lost;
+        stats.fraction_lost = stats.packets_lost as f32 / expected.max(1) as f32;
+        stats
+    }
+
+    /// Glass-to-glass latency of the last frame, from its capture on the caster
+    /// to its decoding here. None until the clock offset is known and a stamped
+    /// frame arrived.
+    pub fn latency(&self) -> Option<Duration> {
+        let (captured, decoded) = (*self.shared.last_frame.lock().unwrap())?;
+        let offset = self.clock_offset()?;
+        Some(Duration::from_micros(
+            offset.to_caster(decoded).saturating_sub(captured),
+        ))
+    }
+
+    /// Offset of the caster's clock, estimated from the ping round trips
+    pub fn clock_offset(&self) -> Option<ClockOffset> {
+        self.shared
+            .connection_client
+            .lock()
+            .unwrap()
+            .as_ref()?
+            .clock_offset()
+    }
+
+    /// Receivers the stream is forwarded to, None when not relaying
+    pub fn relay_receivers(&self) -> Option<usize> {
+        self.shared
+            .relay
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(Relay::receivers)
+    }
+
+    /// True when the relay was stopped because the caster asks for approval,
+    /// which the relay couldn't enforce on its own receivers
+    pub fn is_relay_refused(&self) -> bool {
+        self.shared.relay_refused.load(Ordering::Relaxed)
+    }
+
+    /// How the media is currently received, see `StreamingClientConfig::websocket_fallback`
+    pub fn media_transport(&self) -> MediaTransport {
+        *self.shared.transport.lock().unwrap()
+    }
+
+    pub fn is_caster_paused(&self) -> bool {
+        self.shared.caster_paused.load(Ordering::Relaxed)
+    }
+
+    pub fn is_caster_blanked(&self) -> bool {
+        self.shared.caster_blanked.load(Ordering::Relaxed)
+    }
+
+    /// True until the caster allows this receiver, when it asks for approval
+    pub fn is_awaiting_approval(&self) -> bool {
+        self.shared.awaiting_approval.load(Ordering::Relaxed)
+    }
+
+    /// Error reported by the caster, usually the reason why the connection was closed
+    pub fn take_error(&self) -> Option<StreamingClientError> {
+        self.shared.error.lock().unwrap().take()
     }
 }
 
 impl Drop for StreamingClient {
     fn drop(&mut self) {
-        if self.is_connected() {
-            self.pipeline.send_event(gst::event::Eos::new());
-            self.pipeline
-                .bus()
-                .unwrap()
-                .timed_pop_filtered(gst::ClockTime::NONE, &[gst::MessageType::Eos]);
-        }
-        let _ = self.pipeline.set_state(gst::State::Null);
+        // the pipeline is still running, even when the media timed out
+        self.shared.stop_pipeline();
+        self.shared.connection_client.lock().unwrap().take();
     }
 }
@@ -0,0 +1,193 @@
+//! HLS output of the caster, so that the stream can be followed from any
+//! browser at the cost of a few seconds of latency.
+//!
+//! hlssink2 muxes the H.264 of the caster's encoder into rolling MPEG-TS
+//! segments and keeps a playlist of the last ones, both written to a directory
+//! that a built-in HTTP server serves along with a small player page. The page
+//! relies on the browser's native HLS support (Safari, Chrome on Android and,
+//! recently, the desktop Chromium based ones), nothing is loaded from elsewhere.
+//! Other browsers can open the playlist in a player such as VLC.
+//!
+//! Like RTSP it has no authentication, so the caster refuses to enable it when
+//! the receivers are admitted with a passphrase or approval, and the segments
+//! are served over plain HTTP even when the RTP output uses SRTP.
+//! To check it on the caster's host:
+//!
+//! ```text
+//! curl http://127.0.0.1:8080/playlist.m3u8
+//! ```
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use gst::prelude::*;
+use gstreamer as gst;
+
+use crate::connection::http::{HttpServer, Response};
+use crate::connection::{host_port, local_ip};
+
+pub const DEFAULT_HLS_PORT: u16 = 8080;
+
+const PLAYLIST: &str = "playlist.m3u8";
+const SEGMENTS: &str = "segment%05d.ts";
+
+/// Player page, browsers without native HLS support are pointed to the playlist
+const PLAYER_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>rust-streamer</title>
+<style>body { margin: 0; background: black; color: white; font-family: sans-serif; } video { width: 100vw; height: 100vh; } a { color: inherit; }</style>
+</head>
+<body>
+<video id="video" autoplay muted controls playsinline></video>
+<p id="unsupported" hidden>This browser can't play HLS, open <a href="playlist.m3u8">the playlist</a> in a player such as VLC.</p>
+<script>
+const video = document.getElementById("video");
+if (video.canPlayType("application/vnd.apple.mpegurl")) {
+  video.src = "playlist.m3u8";
+} else {
+  video.hidden = true;
+  document.getElementById("unsupported").hidden = false;
+}
+</script>
+</body>
+</html>
+"#;
+
+#[derive(Clone, Debug)]
+pub struct HlsConfig {
+    /// Where the segments and the playlist are written, the ones of previous
+    /// sessions are deleted and any other file is left alone
+    pub directory: PathBuf,
+    /// Port of the HTTP server
+    pub port: u16,
+    /// Seconds of video per segment, the latency is a few times this
+    pub target_duration: u32,
+    /// Segments listed in the playlist
+    pub playlist_length: u32,
+}
+
+impl Default for HlsConfig {
+    fn default() -> Self {
+        Self {
+            directory: std::env::temp_dir().join("rust-streamer-hls"),
+            port: DEFAULT_HLS_PORT,
+            target_duration: 2,
+            playlist_length: 5,
+        }
+    }
+}
+
+/// Serves the segments until dropped
+pub(crate) struct HlsOutput {
+    port: u16,
+    _http_server: HttpServer,
+}
+
+impl HlsOutput {
+    /// Branch of the caster's pipeline fed by the encoder's tee
+    pub fn pipeline_branch(config: &HlsConfig) -> String {
+        // the segments are kept a bit longer than listed, for the players
+        // still downloading the oldest ones
+        format!(
+            " h264. ! queue ! h264parse ! hlssink2 name=hls target-duration={} playlist-length={} max-files={}",
+            config.target_duration,
+            config.playlist_length,
+            config.playlist_length * 2
+        )
+    }
+
+    /// Points the `hlssink2` of the branch to the directory and starts serving it
+    pub fn new(config: &HlsConfig, hlssink: &gst::Element) -> io::Result<Self> {
+        fs::create_dir_all(&config.directory)?;
+        for entry in fs::read_dir(&config.directory)? {
+            let path = entry?.path();
+            if is_served(&path) {
+                fs::remove_file(path)?;
+            }
+        }
+
+        hlssink.set_property(
+            "location",
+            config.directory.join(SEGMENTS).to_string_lossy().as_ref(),
+        );
+        hlssink.set_property(
+            "playlist-location",
+            config.directory.join(PLAYLIST).to_string_lossy().as_ref(),
+        );
+
+        let directory = config.directory.clone();
+        let http_server = HttpServer::new(config.port, move |request| {
+            let name = request.path.trim_start_matches('/');
+            if name.is_empty() || name == "index.html" {
+                return Some(Response::new("text/html; charset=utf-8", PLAYER_PAGE));
+            }
+            // only the files hlssink2 writes, nothing outside the directory
+            let path = directory.join(name);
+            if name.contains(['/', '\\']) || !is_served(&path) {
+                return None;
+            }
+            let content_type = if name == PLAYLIST {
+                "application/vnd.apple.mpegurl"
+            } else {
+                "video/mp2t"
+            };
+            fs::read(path)
+                .ok()
+                .map(|body| Response::new(content_type, body))
+        })?;
+
+        let output = Self {
+            port: config.port,
+            _http_server: http_server,
+        };
+        println!(
+            "HLS written to {} and served on {}",
+            config.directory.display(),
+            output.url()
+        );
+        Ok(output)
+    }
+
+    /// URL of the player page on the interface of the default route
+    pub fn url(&self) -> String {
+        let host = local_ip().map_or_else(|| "localhost".to_string(), |ip| ip.to_string());
+        format!("http://{}/", host_port(&host, self.port))
+    }
+}
+
+/// Whether the file is one hlssink2 writes, the playlist or a segment named after `SEGMENTS`
+fn is_served(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    name == PLAYLIST
+        || name
+            .strip_prefix("segment")
+            .and_then(|name| name.strip_suffix(".ts"))
+            .is_some_and(|index| index.len() >= 5 && index.bytes().all(|b| b.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_files_of_hlssink2_are_served() {
+        let directory = Path::new("/tmp/hls");
+        for name in [PLAYLIST, "segment00000.ts", "segment123456.ts"] {
+            assert!(is_served(&directory.join(name)), "{}", name);
+        }
+        for name in [
+            "movie.ts",
+            "segment.ts",
+            "segment0001a.ts",
+            "segment00000.mp4",
+            "index.html",
+        ] {
+            assert!(!is_served(&directory.join(name)), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Capture time of the frames, carried to the receivers in an RTP header
+//! extension so that they can measure the glass-to-glass latency.
+//!
+//! The caster attaches its wall clock time to every raw frame as a reference
+//! timestamp meta, the encoder keeps it and the payloader writes it in the
+//! NTP-64 extension. The receiver's depayloader reads it back into the same
+//! meta, which survives the decoder.
+
+use gst::prelude::*;
+use gstreamer as gst;
+
+use crate::connection::clock::now_micros;
+
+/// RTP header extension with the capture time of the frame, in NTP format
+pub(crate) const CAPTURE_TIME_EXTENSION: &str = "urn:ietf:params:rtp-hdrext:ntp-64";
+
+/// Microseconds from the NTP epoch (1900) to the UNIX one (1970)
+const NTP_UNIX_OFFSET_US: u64 = 2_208_988_800_000_000;
+
+/// What the NTP-64 extension is written from and read into
+const NTP_META_CAPS: &str = "timestamp/x-ntp";
+
+/// Stamps every buffer going through the pad with the current time
+pub(crate) fn stamp_capture_time(pad: &gst::Pad) {
+    let caps = gst::Caps::new_empty_simple(NTP_META_CAPS);
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        if let Some(buffer) = info.buffer_mut() {
+            let ntp = gst::ClockTime::from_useconds(now_micros() + NTP_UNIX_OFFSET_US);
+            gst::ReferenceTimestampMeta::add(buffer.make_mut(), &caps, ntp, gst::ClockTime::NONE);
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Capture time of the frame in the caster's clock, in microseconds since the
+/// UNIX epoch. None when the caster didn't stamp it.
+pub(crate) fn capture_time(buffer: &gst::BufferRef) -> Option<u64> {
+    buffer
+        .iter_meta::<gst::ReferenceTimestampMeta>()
+        .find(|meta| {
+            meta.reference()
+                .structure(0)
+                .is_some_and(|structure| structure.name() == NTP_META_CAPS)
+        })
+        .map(|meta| {
+            meta.timestamp()
+                .useconds()
+                .saturating_sub(NTP_UNIX_OFFSET_US)
+        })
+}
@@ -0,0 +1,319 @@
+//! Relay mode of a receiver: the H.264 it gets from the caster is payloaded
+//! again and forwarded to its own receivers, without decoding nor re-encoding
+//! it. Relays can be chained to cross subnets or to offload the caster's uplink.
+//!
+//! The relayed stream goes through an rtpbin of its own, like the caster's one:
+//! it's encrypted with a key of the relay's session, the lost packets are resent
+//! on request and the receivers that can't get UDP get it over the websocket.
+//!
+//! By default it asks its receivers for the caster's passphrase and, when the
+//! caster's signaling uses TLS, it uses TLS too with a self-signed certificate.
+//! It has no way to approve, kick nor ban receivers, so it stops as soon as the
+//! caster asks for approval.
+//!
+//! The whole chain runs on a single host with a caster, a receiver of it with
+//! the relay enabled and a receiver connecting to the relay's port, as long as
+//! every receiver has its own media port (e.g. 9001 and 9101).
+
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use gst::prelude::*;
+use gstreamer as gst;
+use gstreamer_app as gst_app;
+use message_io::network::Endpoint;
+use rand::RngCore;
+
+use super::latency::CAPTURE_TIME_EXTENSION;
+use super::listen_udp;
+use crate::connection::message::{ControlMessage, MediaTransport, SRTP_KEY_LEN};
+use crate::connection::server::{ConnectionServer, ConnectionServerConfig};
+use crate::connection::tls::TlsIdentity;
+use crate::connection::DEFAULT_TIMEOUT;
+
+pub const DEFAULT_RELAY_PORT: u16 = 9010;
+/// Port the relay receives the RTCP reports and retransmission requests of its receivers on
+pub const DEFAULT_RELAY_RTCP_PORT: u16 = 9015;
+
+/// The receivers of a relay connect to it like to a caster. The latency they
+/// measure assumes the relay's clock is in sync with the caster's one.
+#[derive(Clone, Debug)]
+pub struct RelayConfig {
+    /// Port the relay's signaling websocket listens on
+    pub port: u16,
+    /// UDP port the RTCP of the relay's receivers arrives on
+    pub rtcp_port: u16,
+    /// When set the relay's receivers must provide this passphrase, otherwise
+    /// the one used with the caster, if any
+    pub passphrase: Option<String>,
+    /// When set the relay's signaling is encrypted, its receivers must enable TLS
+    /// too. Set to a self-signed certificate when the caster's signaling uses TLS.
+    pub tls: Option<TlsIdentity>,
+    /// Encrypts the relayed media with a key generated for the relay's session
+    pub srtp: bool,
+    /// Receivers whose signaling is silent for longer than this are dropped
+    pub timeout: Duration,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_RELAY_PORT,
+            rtcp_port: DEFAULT_RELAY_RTCP_PORT,
+            passphrase: None,
+            tls: None,
+            srtp: true,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/// State of the caster, mirrored to the relay's receivers
+#[derive(Default)]
+struct Mirrored {
+    paused: bool,
+    blanked: bool,
+}
+
+#[derive(Clone, Copy)]
+struct RelayPeer {
+    ip: IpAddr,
+    media_port: u16,
+    transport: MediaTransport,
+}
+
+/// UDP destinations of the relayed stream, RTCP goes to the port after the media one
+#[derive(Clone)]
+struct Destinations {
+    multiudpsink: gst::Element,
+    rtcpsink: gst::Element,
+}
+
+impl Destinations {
+    fn update(&self, signal: &str, peer: &RelayPeer) {
+        for (sink, port) in [
+            (&self.multiudpsink, peer.media_port),
+            (&self.rtcpsink, peer.media_port.wrapping_add(1)),
+        ] {
+            sink.emit_by_name_with_values(
+                signal,
+                &[peer.ip.to_string().into(), (port as i32).into()],
+            );
+        }
+    }
+}
+
+pub(crate) struct Relay {
+    connection_server: ConnectionServer,
+    peers: Arc<Mutex<HashMap<Endpoint, RelayPeer>>>,
+    mirrored: Arc<Mutex<Mirrored>>,
+}
+
+impl Relay {
+    /// Branch of the receiver's pipeline fed by the tee of the depayloaded H.264
+    pub fn pipeline_branch() -> &'static str {
+        " t. ! queue ! rtph264pay name=relaypay config-interval=-1 pt=96 ! capsfilter name=relayextmap ! rtprtxsend name=relayrtx ! relaybin.send_rtp_sink_0 rtpbin name=relaybin rtp-profile=avpf relaybin.send_rtp_src_0 ! srtpenc name=relaysrtpenc ! tee name=relayrtp ! queue ! multiudpsink name=relaysink sync=false async=false relayrtp. ! queue ! appsink name=relaywssink sync=false relaybin.send_rtcp_src_0 ! multiudpsink name=relayrtcpsink sync=false async=false funnel name=relayrtcpsrc ! relaybin.recv_rtcp_sink_0"
+    }
+
+    /// Forwards the packets of the branch to the receivers connecting on the
+    /// configured port, `on_join` has to get a keyframe from the caster
+    pub fn new(
+        config: RelayConfig,
+        pipeline: &gst::Pipeline,
+        on_join: impl Fn() + Send + Sync + 'static,
+    ) -> io::Result<Self> {
+        let on_join = Arc::new(on_join);
+        // the capture time read from the caster's packets is written in the relayed ones
+        pipeline.by_name("relayextmap").unwrap().set_property(
+            "caps",
+            gst::Caps::builder("application/x-rtp")
+                .field("extmap-1", CAPTURE_TIME_EXTENSION)
+                .build(),
+        );
+        pipeline.by_name("relayrtx").unwrap().set_property(
+            "payload-type-map",
+            gst::Structure::builder("application/x-rtp-pt-map")
+                .field("96", 97u32)
+                .build(),
+        );
+        listen_udp(pipeline, "relayrtcpsrc", config.rtcp_port).map_err(io::Error::other)?;
+
+        let srtpenc = pipeline.by_name("relaysrtpenc").unwrap();
+        let srtp_key = config.srtp.then(|| {
+            let mut key = vec![0; SRTP_KEY_LEN];
+            rand::thread_rng().fill_bytes(&mut key);
+            key
+        });
+        match &srtp_key {
+            Some(key) => srtpenc.set_property("key", gst::Buffer::from_slice(key.clone())),
+            None => {
+                for property in ["rtp-cipher", "rtp-auth"] {
+                    srtpenc.set_property_from_str(property, "null");
+                }
+            }
+        }
+        // as on the caster, the RTCP reports aren't encrypted
+        for property in ["rtcp-cipher", "rtcp-auth"] {
+            srtpenc.set_property_from_str(property, "null");
+        }
+
+        let destinations = Destinations {
+            multiudpsink: pipeline.by_name("relaysink").unwrap(),
+            rtcpsink: pipeline.by_name("relayrtcpsink").unwrap(),
+        };
+        let wssink = pipeline
+            .by_name("relaywssink")
+            .unwrap()
+            .dynamic_cast::<gst_app::AppSink>()
+            .unwrap();
+
+        let peers: Arc<Mutex<HashMap<Endpoint, RelayPeer>>> = Arc::new(Mutex::new(HashMap::new()));
+        let mirrored = Arc::new(Mutex::new(Mirrored::default()));
+
+        let peers_clone = peers.clone();
+        let peers_clone2 = peers.clone();
+        let peers_clone3 = peers.clone();
+        let mirrored_clone = mirrored.clone();
+        let destinations_clone = destinations.clone();
+        let destinations_clone2 = destinations.clone();
+        let on_join_clone = on_join.clone();
+        let rtcp_port = config.rtcp_port;
+        let connection_server = ConnectionServer::new(
+            ConnectionServerConfig {
+                port: config.port,
+                secret: config.passphrase,
+                timeout: config.timeout,
+                tls: config.tls,
+                viewer: None,
+            },
+            move |handle, endpoint, hello| {
+                let peer = RelayPeer {
+                    ip: handle.peer_addr(endpoint).ip(),
+                    media_port: hello.media_port,
+                    transport: MediaTransport::Udp,
+                };
+                destinations.update("add", &peer);
+                peers_clone.lock().unwrap().insert(endpoint, peer);
+                let mirrored = mirrored_clone.lock().unwrap();
+                handle.send(
+                    endpoint,
+                    &ControlMessage::StreamInfo {
+                        codec: "H264".to_string(),
+                        framerate: 30,
+                        paused: mirrored.paused,
+                        blanked: mirrored.blanked,
+                        srtp_key: srtp_key.clone(),
+                        multicast: None,
+                        rtcp_port,
+                        // only the video is relayed
+                        audio: false,
+                    },
+                );
+                println!(
+                    "Relaying to {}:{} ({})",
+                    peer.ip, peer.media_port, hello.name
+                );
+                on_join();
+            },
+            move |handle, endpoint, message| match message {
+                // only the caster could adapt the bitrate
+                ControlMessage::ReceptionReport(_) => {}
+                ControlMessage::SetMediaTransport(transport) => {
+                    let mut peers = peers_clone3.lock().unwrap();
+                    let Some(peer) = peers.get_mut(&endpoint) else {
+                        return;
+                    };
+                    match (peer.transport, transport) {
+                        (MediaTransport::Udp, MediaTransport::Websocket) => {
+                            destinations_clone.update("remove", peer)
+                        }
+                        (MediaTransport::Websocket, MediaTransport::Udp) => {
+                            destinations_clone.update("add", peer)
+                        }
+                        // there is no multicast group to join
+                        _ => return,
+                    }
+                    peer.transport = transport;
+                    println!("Relaying to {} over {:?}", peer.ip, transport);
+                    // nothing can be decoded before the next keyframe
+                    on_join_clone();
+                }
+                message => println!(
+                    "Message from {}: {:?}",
+                    handle.peer_addr(endpoint).ip(),
+                    message
+                ),
+            },
+            move |endpoint, _| {
+                if let Some(peer) = peers_clone2.lock().unwrap().remove(&endpoint) {
+                    if peer.transport == MediaTransport::Udp {
+                        destinations_clone2.update("remove", &peer);
+                    }
+                    println!("Stopped relaying to {}:{}", peer.ip, peer.media_port);
+                }
+            },
+        )?;
+
+        // the same packets, to the receivers that can't get UDP
+        let handle = connection_server.handle();
+        let peers_clone = peers.clone();
+        wssink.set_callbacks(
+            gst_app::AppSinkCallbacks::builder()
+                .new_sample(move |appsink| {
+                    let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    let endpoints: Vec<Endpoint> = peers_clone
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter(|(_, peer)| peer.transport == MediaTransport::Websocket)
+                        .map(|(endpoint, _)| *endpoint)
+                        .collect();
+                    if endpoints.is_empty() {
+                        return Ok(gst::FlowSuccess::Ok);
+                    }
+                    let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
+                    let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                    let message = ControlMessage::Media(map.as_slice().to_vec());
+                    for endpoint in endpoints {
+                        handle.send(endpoint, &message);
+                    }
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+        println!("Relay listening on port {}", config.port);
+
+        Ok(Self {
+            connection_server,
+            peers,
+            mirrored,
+        })
+    }
+
+    /// Tells the relay's receivers when the caster pauses or blanks the screen
+    pub fn mirror(&self, paused: bool, blanked: bool) {
+        let mut mirrored = self.mirrored.lock().unwrap();
+        if mirrored.paused != paused {
+            self.connection_server.broadcast(if paused {
+                &ControlMessage::Paused
+            } else {
+                &ControlMessage::Resumed
+            });
+        }
+        if mirrored.blanked != blanked {
+            self.connection_server.broadcast(if blanked {
+                &ControlMessage::Blanked
+            } else {
+                &ControlMessage::Restored
+            });
+        }
+        *mirrored = Mirrored { paused, blanked };
+    }
+
+    pub fn receivers(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+}
@@ -0,0 +1,174 @@
+//! RTMP push of the caster to a media server (nginx-rtmp, MediaMTX, Owncast...),
+//! in parallel with the receivers on the local network.
+//!
+//! The H.264 of the caster's encoder is muxed into FLV by a pipeline of its
+//! own, so that a refused or dropped connection is retried without stopping
+//! the other outputs. With a local server the push is checked with:
+//!
+//! ```text
+//! ffplay rtmp://localhost/live/screen
+//! ```
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use gst::prelude::*;
+use gstreamer as gst;
+use gstreamer_app as gst_app;
+
+use gst::glib;
+
+use super::H264_CAPS;
+
+/// Wait before connecting again after the server refused or dropped the stream
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+pub struct RtmpConfig {
+    /// Application URL of the ingest server, e.g. `rtmp://localhost/live`
+    pub url: String,
+    /// Appended to the URL, it's what the server publishes the stream as
+    pub stream_key: String,
+}
+
+impl Default for RtmpConfig {
+    fn default() -> Self {
+        Self {
+            url: "rtmp://localhost/live".to_string(),
+            stream_key: "screen".to_string(),
+        }
+    }
+}
+
+impl RtmpConfig {
+    /// Full URL the stream is published at, the stream key appended to the application
+    fn location(&self) -> String {
+        format!("{}/{}", self.url.trim_end_matches('/'), self.stream_key)
+    }
+}
+
+/// Pushes the stream until dropped
+pub(crate) struct RtmpOutput {
+    url: String,
+    pipeline: gst::Pipeline,
+    appsrc: gst_app::AppSrc,
+    running: Arc<AtomicBool>,
+}
+
+impl RtmpOutput {
+    /// `on_connect` has to get a keyframe from the encoder, the server can't
+    /// decode anything before it
+    pub fn new(
+        config: &RtmpConfig,
+        on_connect: impl Fn() + Send + 'static,
+    ) -> Result<Self, glib::BoolError> {
+        let appsrc = gst_app::AppSrc::builder()
+            .is_live(true)
+            .format(gst::Format::Time)
+            .do_timestamp(true)
+            .caps(&gst::Caps::from_str(H264_CAPS)?)
+            .build();
+        let parse = gst::ElementFactory::make("h264parse").build()?;
+        let mux = gst::ElementFactory::make("flvmux")
+            .property("streamable", true)
+            .build()?;
+        let sink = gst::ElementFactory::make("rtmp2sink")
+            .property("location", config.location())
+            .property("sync", false)
+            .property("async", false)
+            .build()?;
+
+        let pipeline = gst::Pipeline::new();
+        pipeline.add_many([appsrc.upcast_ref(), &parse, &mux, &sink])?;
+        gst::Element::link_many([appsrc.upcast_ref(), &parse, &mux, &sink])?;
+        pipeline
+            .set_state(gst::State::Playing)
+            .map_err(|e| glib::bool_error!("Failed to start the RTMP output: {}", e))?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let running_clone = running.clone();
+        let pipeline_clone = pipeline.downgrade();
+        let url = config.url.clone();
+        thread::spawn(move || {
+            while running_clone.load(Ordering::Relaxed) {
+                let Some(pipeline) = pipeline_clone.upgrade() else {
+                    return;
+                };
+                let message = pipeline.bus().unwrap().timed_pop_filtered(
+                    gst::ClockTime::from_seconds(1),
+                    &[gst::MessageType::Error, gst::MessageType::Eos],
+                );
+                let Some(message) = message else {
+                    continue;
+                };
+                if let gst::MessageView::Error(e) = message.view() {
+                    println!(
+                        "RTMP push to {} failed: {}, retrying in {} seconds",
+                        url,
+                        e.error(),
+                        RETRY_DELAY.as_secs()
+                    );
+                }
+                let _ = pipeline.set_state(gst::State::Null);
+                thread::sleep(RETRY_DELAY);
+                if running_clone.load(Ordering::Relaxed)
+                    && pipeline.set_state(gst::State::Playing).is_ok()
+                {
+                    on_connect();
+                }
+            }
+        });
+
+        println!("Pushing the stream to {}", config.url);
+        Ok(Self {
+            url: config.url.clone(),
+            pipeline,
+            appsrc,
+            running,
+        })
+    }
+
+    /// URL of the ingest server, without the stream key
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Forwards a frame of the encoder to the server
+    pub fn push(&self, buffer: &gst::Buffer) {
+        // the output's running time has nothing to do with the caster's, appsrc stamps it again
+        let mut buffer = buffer.copy();
+        {
+            let buffer = buffer.make_mut();
+            buffer.set_pts(gst::ClockTime::NONE);
+            buffer.set_dts(gst::ClockTime::NONE);
+        }
+        // refused while reconnecting
+        let _ = self.appsrc.push_buffer(buffer);
+    }
+}
+
+impl Drop for RtmpOutput {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_key_is_appended_to_the_url() {
+        let config = RtmpConfig::default();
+        assert_eq!(config.location(), "rtmp://localhost/live/screen");
+        let config = RtmpConfig {
+            url: "rtmps://live.example.com/app/".to_string(),
+            stream_key: "abc123".to_string(),
+        };
+        assert_eq!(config.location(), "rtmps://live.example.com/app/abc123");
+    }
+}
@@ -0,0 +1,165 @@
+//! RTSP output of the caster, so that stock players (VLC, ffplay, `rtspsrc`)
+//! can watch without running this application.
+//!
+//! The H.264 of the caster's encoder is pushed into the appsrc of the media
+//! served by gst-rtsp-server, the screen is encoded only once. It has no
+//! authentication, so the caster refuses to enable it when the receivers are
+//! admitted with a passphrase or approval. It sends plain RTP, even when the
+//! caster's own RTP output is encrypted with SRTP.
+//!
+//! To check it on the caster's host:
+//!
+//! ```text
+//! gst-launch-1.0 rtspsrc location=rtsp://127.0.0.1:8554/screen latency=100 ! rtph264depay ! avdec_h264 ! videoconvert ! autovideosink
+//! ```
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use gst::prelude::*;
+use gst_rtsp_server::prelude::*;
+use gstreamer as gst;
+use gstreamer_app as gst_app;
+use gstreamer_rtsp_server as gst_rtsp_server;
+
+use gst::glib;
+
+use super::H264_CAPS;
+use crate::connection::{host_port, local_ip};
+
+pub const DEFAULT_RTSP_PORT: u16 = 8554;
+
+#[derive(Clone, Debug)]
+pub struct RtspConfig {
+    pub port: u16,
+    /// Mount point of the stream, starting with a slash
+    pub path: String,
+}
+
+impl Default for RtspConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_RTSP_PORT,
+            path: "/screen".to_string(),
+        }
+    }
+}
+
+/// Serves the stream until dropped
+pub(crate) struct RtspOutput {
+    port: u16,
+    path: String,
+    main_loop: glib::MainLoop,
+    context: glib::MainContext,
+    source: Option<glib::SourceId>,
+    /// appsrc of every prepared media, the encoded frames are pushed into them
+    sources: Arc<Mutex<Vec<gst_app::AppSrc>>>,
+}
+
+impl RtspOutput {
+    /// `on_play` has to get a keyframe from the encoder, for the client that is starting
+    pub fn new(
+        config: &RtspConfig,
+        on_play: impl Fn() + Send + Sync + 'static,
+    ) -> Result<Self, glib::BoolError> {
+        let server = gst_rtsp_server::RTSPServer::new();
+        server.set_service(&config.port.to_string());
+
+        let factory = gst_rtsp_server::RTSPMediaFactory::new();
+        factory.set_launch(&format!(
+            "( appsrc name=h264src is-live=true format=time do-timestamp=true caps=\"{}\" ! h264parse ! rtph264pay name=pay0 pt=96 config-interval=-1 )",
+            H264_CAPS
+        ));
+        // every client watches the same media, fed by the same appsrc
+        factory.set_shared(true);
+
+        let sources = Arc::new(Mutex::new(Vec::new()));
+        let sources_clone = sources.clone();
+        factory.connect_media_configure(move |_, media| {
+            let appsrc = media
+                .element()
+                .dynamic_cast::<gst::Bin>()
+                .ok()
+                .and_then(|bin| bin.by_name("h264src"))
+                .and_then(|appsrc| appsrc.dynamic_cast::<gst_app::AppSrc>().ok());
+            if let Some(appsrc) = appsrc {
+                sources_clone.lock().unwrap().push(appsrc.clone());
+                // the media is torn down when its last client leaves
+                let sources = sources_clone.clone();
+                media.connect_unprepared(move |_| {
+                    sources.lock().unwrap().retain(|source| *source != appsrc);
+                });
+            }
+        });
+
+        let on_play = Arc::new(on_play);
+        server.connect_client_connected(move |_, client| {
+            let on_play = on_play.clone();
+            client.connect_play_request(move |_, _| on_play());
+        });
+
+        server
+            .mount_points()
+            .ok_or_else(|| glib::bool_error!("The RTSP server has no mount points"))?
+            .add_factory(&config.path, factory);
+
+        // the server runs on its own main context, the application doesn't have a GLib loop
+        let context = glib::MainContext::new();
+        let main_loop = glib::MainLoop::new(Some(&context), false);
+        let source = server.attach(Some(&context))?;
+        let main_loop_clone = main_loop.clone();
+        let context_clone = context.clone();
+        thread::spawn(move || {
+            let _ = context_clone.with_thread_default(|| main_loop_clone.run());
+        });
+
+        let output = Self {
+            port: config.port,
+            path: config.path.clone(),
+            main_loop,
+            context,
+            source: Some(source),
+            sources,
+        };
+        println!("RTSP server on {}", output.url());
+        Ok(output)
+    }
+
+    /// URL of the stream on the interface of the default route
+    pub fn url(&self) -> String {
+        let host = local_ip().map_or_else(|| "localhost".to_string(), |ip| ip.to_string());
+        format!("rtsp://{}{}", host_port(&host, self.port), self.path)
+    }
+
+    /// Forwards a frame of the encoder to the clients
+    pub fn push(&self, buffer: &gst::Buffer) {
+        let sources = self.sources.lock().unwrap();
+        if sources.is_empty() {
+            return;
+        }
+        // the media's running time has nothing to do with the caster's, appsrc stamps it again
+        let mut buffer = buffer.copy();
+        {
+            let buffer = buffer.make_mut();
+            buffer.set_pts(gst::ClockTime::NONE);
+            buffer.set_dts(gst::ClockTime::NONE);
+        }
+        for appsrc in sources.iter() {
+            // refused while the media is still being prepared
+            let _ = appsrc.push_buffer(buffer.clone());
+        }
+    }
+}
+
+impl Drop for RtspOutput {
+    fn drop(&mut self) {
+        if let Some(source) = self
+            .source
+            .take()
+            .and_then(|id| self.context.find_source_by_id(&id))
+        {
+            source.destroy();
+        }
+        self.main_loop.quit();
+    }
+}
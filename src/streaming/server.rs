@@ -1,14 +1,41 @@
 use byte_slice_cast::*;
+use chrono::prelude::*;
+use message_io::network::Endpoint;
+use std::collections::{HashMap, HashSet};
 use std::io;
-use std::sync::Arc;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, Weak,
+};
+use std::thread;
+use std::time::Duration;
 
 use gst::prelude::*;
 use gst::{element_error, glib};
 use gstreamer as gst;
 use gstreamer_app as gst_app;
+use rand::RngCore;
 use thiserror::Error;
 
-use crate::connection::server::ConnectionServer;
+use super::audio::{AudioConfig, AUDIO_PORT_OFFSET};
+use super::bitrate::{BitrateChange, BitrateConfig, BitrateController, ADAPTATION_INTERVAL};
+use super::hls::{HlsConfig, HlsOutput};
+use super::latency::{stamp_capture_time, CAPTURE_TIME_EXTENSION};
+use super::rtmp::{RtmpConfig, RtmpOutput};
+use super::rtsp::{RtspConfig, RtspOutput};
+use super::srt::SrtConfig;
+use super::stats::{resolution, rtp_sources, PeerStats, RateMeter, ServerStats};
+use super::{listen_udp, H264_CAPS};
+use crate::connection::discovery::{Announcer, Beacon, DEFAULT_DISCOVERY_PORT};
+use crate::connection::message::PROTOCOL_VERSION;
+use crate::connection::message::{
+    ControlMessage, Hello, MediaTransport, ReceptionReport, SRTP_KEY_LEN,
+};
+use crate::connection::server::{ConnectionServer, ConnectionServerConfig};
+use crate::connection::tls::TlsIdentity;
+use crate::connection::viewer::ViewerConfig;
+use crate::connection::{user_name, DEFAULT_RTCP_PORT, DEFAULT_SIGNALING_PORT, DEFAULT_TIMEOUT};
 
 #[derive(Error, Debug)]
 pub enum StreamingServerError {
@@ -23,6 +50,311 @@ pub enum StreamingServerError {
 
     #[error("Websocket error: {0}")]
     WebsocketError(#[from] io::Error),
+
+    #[error("HLS output error: {0}")]
+    HlsError(io::Error),
+
+    #[error("{0} has no authentication, it can't serve a stream whose receivers are admitted with a passphrase or approval")]
+    UnprotectedOutput(&'static str),
+}
+
+#[derive(Clone, Debug)]
+pub struct StreamingServerConfig {
+    /// Port the signaling websocket listens on
+    pub port: u16,
+    /// Name shown to the receivers discovering the caster
+    pub name: String,
+    /// When set the caster announces itself on the local network, on this port
+    pub discovery_port: Option<u16>,
+    /// When set receivers must provide the same passphrase to get the stream
+    pub passphrase: Option<String>,
+    /// When set every receiver has to be allowed, see `StreamingServer::pending_peers`
+    pub require_approval: bool,
+    /// Receivers whose signaling is silent for longer than this are dropped
+    pub timeout: Duration,
+    /// When set the signaling is encrypted, receivers must enable TLS too
+    pub tls: Option<TlsIdentity>,
+    /// Encrypts the media with a key generated for this session and sent to every admitted receiver
+    pub srtp: bool,
+    /// When set the media is sent once to a multicast group instead of to every receiver
+    pub multicast: Option<MulticastConfig>,
+    /// UDP port the RTCP reports and retransmission requests of the receivers arrive on
+    pub rtcp_port: u16,
+    /// Resends the packets the receivers report as lost (RTX)
+    pub retransmission: bool,
+    /// ULPFEC overhead in percent of the media packets, 0 disables it
+    pub fec_percentage: u32,
+    /// Bounds the encoder bitrate is adapted within, following the receivers' reports
+    pub bitrate: BitrateConfig,
+    /// When set the stream is also served over RTSP, for players that don't speak our signaling
+    pub rtsp: Option<RtspConfig>,
+    /// When set the stream is also written as HLS and served over HTTP, for browsers
+    pub hls: Option<HlsConfig>,
+    /// When set browsers can watch the preview frames on a page, see `ViewerConfig`
+    pub viewer: Option<ViewerConfig>,
+    /// When set the stream is also sent over SRT, to an ingest point or to the callers
+    pub srt: Option<SrtConfig>,
+    /// When set the stream is also pushed to an RTMP server
+    pub rtmp: Option<RtmpConfig>,
+    /// When set the audio is captured and sent as well, see `AudioConfig`
+    pub audio: Option<AudioConfig>,
+}
+
+impl StreamingServerConfig {
+    /// True when the receivers have to be admitted, with the passphrase or by approval
+    pub fn is_protected(&self) -> bool {
+        self.passphrase.is_some() || self.require_approval
+    }
+
+    /// Refuses the outputs that would let anyone watch a protected stream
+    fn check_outputs(&self) -> Result<(), StreamingServerError> {
+        if !self.is_protected() {
+            return Ok(());
+        }
+        if self.rtsp.is_some() {
+            return Err(StreamingServerError::UnprotectedOutput("RTSP"));
+        }
+        if self.hls.is_some() {
+            return Err(StreamingServerError::UnprotectedOutput("HLS"));
+        }
+        if self.viewer.is_some() {
+            return Err(StreamingServerError::UnprotectedOutput(
+                "The browser viewer",
+            ));
+        }
+        // SRT can only check its passphrase, callers knowing it aren't approved
+        if self
+            .srt
+            .as_ref()
+            .is_some_and(|srt| srt.passphrase.is_none())
+        {
+            return Err(StreamingServerError::UnprotectedOutput(
+                "SRT without a passphrase",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for StreamingServerConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_SIGNALING_PORT,
+            name: user_name(),
+            discovery_port: Some(DEFAULT_DISCOVERY_PORT),
+            passphrase: None,
+            require_approval: false,
+            timeout: DEFAULT_TIMEOUT,
+            tls: None,
+            srtp: true,
+            multicast: None,
+            rtcp_port: DEFAULT_RTCP_PORT,
+            retransmission: true,
+            fec_percentage: 0,
+            bitrate: BitrateConfig::default(),
+            rtsp: None,
+            hls: None,
+            viewer: None,
+            srt: None,
+            rtmp: None,
+            audio: None,
+        }
+    }
+}
+
+/// First of the four ports of the multicast group, after the relay's signaling one
+pub const DEFAULT_MULTICAST_PORT: u16 = 9011;
+
+#[derive(Clone, Debug)]
+pub struct MulticastConfig {
+    /// Group and port the media is sent to, receivers are told to join it
+    pub group: SocketAddr,
+    /// How many routers the packets can cross, 1 keeps them in the local network
+    pub ttl: u32,
+}
+
+impl Default for MulticastConfig {
+    fn default() -> Self {
+        Self {
+            group: (Ipv4Addr::new(239, 255, 42, 100), DEFAULT_MULTICAST_PORT).into(),
+            ttl: 1,
+        }
+    }
+}
+
+/// Parameters of the media, the same for every receiver of this session
+#[derive(Clone, Debug)]
+struct MediaSession {
+    srtp_key: Option<Vec<u8>>,
+    multicast: Option<SocketAddr>,
+    rtcp_port: u16,
+    audio: bool,
+}
+
+/// A receiver waiting for the caster to allow or deny it
+#[derive(Clone, Debug)]
+pub struct PeerRequest {
+    pub endpoint: Endpoint,
+    pub ip: IpAddr,
+    pub name: String,
+}
+
+/// A receiver the stream is being sent to
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub endpoint: Endpoint,
+    pub ip: IpAddr,
+    pub name: String,
+    pub media_port: u16,
+    pub transport: MediaTransport,
+    pub connected_at: DateTime<Local>,
+    /// Last reception report, None until the first one or over the websocket
+    pub reception: Option<ReceptionReport>,
+}
+
+/// Receivers known to the caster, shared with the connection callbacks
+struct Receivers {
+    multiudpsink: gst::Element,
+    /// Sends the sender reports, to the port after the media one
+    rtcpsink: gst::Element,
+    /// Same as the two above for the audio, when it's captured
+    audio_sinks: Option<(gst::Element, gst::Element)>,
+    payloader: gst::Element,
+    /// Receivers aren't added to multiudpsink, the group is
+    multicast: bool,
+    pending: HashMap<Endpoint, (IpAddr, Hello)>,
+    peers: HashMap<Endpoint, Peer>,
+    banned: HashSet<IpAddr>,
+    /// Reports received since the bitrate was last evaluated
+    reports: HashMap<Endpoint, ReceptionReport>,
+}
+
+impl Receivers {
+    fn add(&mut self, endpoint: Endpoint, ip: IpAddr, hello: &Hello) {
+        let transport = if self.multicast {
+            MediaTransport::Multicast
+        } else {
+            self.update_destination("add", ip, hello.media_port);
+            MediaTransport::Udp
+        };
+        self.peers.insert(
+            endpoint,
+            Peer {
+                endpoint,
+                ip,
+                name: hello.name.clone(),
+                media_port: hello.media_port,
+                transport,
+                connected_at: Local::now(),
+                reception: None,
+            },
+        );
+        println!("Connected: {}:{} ({})", ip, hello.media_port, hello.name);
+        self.force_keyframe();
+    }
+
+    /// Adds or removes a destination of the media, RTCP goes to the next port
+    fn update_destination(&self, signal: &str, ip: IpAddr, port: u16) {
+        let mut sinks = vec![
+            (&self.multiudpsink, port),
+            (&self.rtcpsink, port.wrapping_add(1)),
+        ];
+        if let Some((audiosink, audiortcpsink)) = &self.audio_sinks {
+            let port = port.wrapping_add(AUDIO_PORT_OFFSET);
+            sinks.push((audiosink, port));
+            sinks.push((audiortcpsink, port.wrapping_add(1)));
+        }
+        for (sink, port) in sinks {
+            sink.emit_by_name_with_values(signal, &[ip.to_string().into(), (port as i32).into()]);
+        }
+    }
+
+    /// Asks the encoder for an IDR frame right away, so that a new receiver
+    /// doesn't have to wait for the next one. The payloader sends SPS/PPS with
+    /// every IDR (config-interval=-1).
+    fn force_keyframe(&self) {
+        // same structure as gst_video_event_new_upstream_force_key_unit
+        let event = gst::event::CustomUpstream::new(
+            gst::Structure::builder("GstForceKeyUnit")
+                .field("running-time", u64::MAX)
+                .field("all-headers", true)
+                .field("count", 0u32)
+                .build(),
+        );
+        if !self.payloader.static_pad("src").unwrap().send_event(event) {
+            println!("Failed to request a keyframe");
+        }
+    }
+
+    /// Stops sending the stream to the receiver, returns false if it wasn't a peer
+    fn remove(&mut self, endpoint: Endpoint) -> bool {
+        self.pending.remove(&endpoint);
+        self.reports.remove(&endpoint);
+        match self.peers.remove(&endpoint) {
+            Some(peer) => {
+                if peer.transport == MediaTransport::Udp {
+                    self.update_destination("remove", peer.ip, peer.media_port);
+                }
+                println!("Disconnected: {}:{}", peer.ip, peer.media_port);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn set_transport(&mut self, endpoint: Endpoint, transport: MediaTransport) {
+        let peer = match self.peers.get(&endpoint) {
+            Some(peer) if peer.transport != transport => peer.clone(),
+            _ => return,
+        };
+        // only unicast receivers are in multiudpsink
+        let signal = match (peer.transport, transport) {
+            (MediaTransport::Udp, _) => Some("remove"),
+            (_, MediaTransport::Udp) => Some("add"),
+            _ => None,
+        };
+        if let Some(signal) = signal {
+            self.update_destination(signal, peer.ip, peer.media_port);
+        }
+        if let Some(peer) = self.peers.get_mut(&endpoint) {
+            peer.transport = transport;
+            if transport == MediaTransport::Websocket {
+                // TCP doesn't lose packets, the old reports no longer apply
+                peer.reception = None;
+                self.reports.remove(&endpoint);
+            }
+        }
+        println!("Media to {} ({}) over {:?}", peer.ip, peer.name, transport);
+        self.force_keyframe();
+    }
+
+    fn report(&mut self, endpoint: Endpoint, report: ReceptionReport) {
+        match self.peers.get_mut(&endpoint) {
+            Some(peer) if peer.transport != MediaTransport::Websocket => {
+                peer.reception = Some(report);
+                self.reports.insert(endpoint, report);
+            }
+            _ => {}
+        }
+    }
+
+    /// Reports received since the previous call, with the name of the receiver
+    fn take_reports(&mut self) -> Vec<(String, ReceptionReport)> {
+        let peers = &self.peers;
+        self.reports
+            .drain()
+            .filter_map(|(endpoint, report)| Some((peers.get(&endpoint)?.name.clone(), report)))
+            .collect()
+    }
+
+    /// Receivers that get the media over the signaling websocket
+    fn websocket_peers(&self) -> Vec<Endpoint> {
+        self.peers
+            .values()
+            .filter(|peer| peer.transport == MediaTransport::Websocket)
+            .map(|peer| peer.endpoint)
+            .collect()
+    }
 }
 
 pub struct StreamingServer {
@@ -36,28 +368,75 @@ pub struct StreamingServer {
 
     selector: gst::Element,
 
-    _connection_server: ConnectionServer,
+    receivers: Arc<Mutex<Receivers>>,
+
+    paused: Arc<AtomicBool>,
+    blanked: Arc<AtomicBool>,
+
+    media: MediaSession,
+
+    bitrate: Arc<Mutex<BitrateController>>,
+
+    encoder: gst::Element,
+    rtpbin: gst::Element,
+    /// Frames out of the encoder
+    encoded: Arc<RateMeter>,
+
+    connection_server: ConnectionServer,
+
+    rtsp: Option<Arc<RtspOutput>>,
+    hls: Option<HlsOutput>,
+    srt_url: Option<String>,
+    rtmp: Option<Arc<RtmpOutput>>,
+
+    _announcer: Option<Announcer>,
 }
 
 impl StreamingServer {
     pub fn new(
         mut image_parser: impl FnMut(&[u8]) + Send + 'static,
+        config: StreamingServerConfig,
     ) -> Result<Self, StreamingServerError> {
+        config.check_outputs()?;
         gst::init()?;
 
-        let pipeline_string = if cfg!(target_os = "windows") {
-            "input-selector name=i ! tee name=t ! queue ! videoconvert ! x264enc tune=zerolatency ! rtph264pay ! multiudpsink name=s t. ! queue ! videoconvert ! jpegenc ! appsink max-buffers=1 caps=image/jpeg name=videosink d3d11screencapturesrc show-cursor=true name=src ! video/x-raw,framerate=30/1 ! i.sink_0 videotestsrc pattern=white ! video/x-raw,framerate=30/1 ! i.sink_1"
+        let mut pipeline_string = if cfg!(target_os = "windows") {
+            format!("input-selector name=i ! tee name=t ! queue ! videoconvert ! x264enc name=enc tune=zerolatency ! {H264_CAPS} ! tee name=h264 ! queue ! rtph264pay name=pay config-interval=-1 pt=96 ! capsfilter name=extmap ! rtpulpfecenc name=fec pt=122 ! rtprtxsend name=rtx ! rtpbin.send_rtp_sink_0 rtpbin name=rtpbin rtp-profile=avpf rtpbin.send_rtp_src_0 ! srtpenc name=srtpenc ! tee name=rtp ! queue ! multiudpsink name=s rtp. ! queue ! appsink name=rtpsink sync=false rtpbin.send_rtcp_src_0 ! multiudpsink name=rtcpsink sync=false async=false funnel name=rtcpsrc ! rtpbin.recv_rtcp_sink_0 t. ! queue ! videoconvert ! jpegenc ! appsink max-buffers=1 caps=image/jpeg name=videosink d3d11screencapturesrc show-cursor=true name=src ! video/x-raw,framerate=30/1 ! i.sink_0 videotestsrc pattern=white ! video/x-raw,framerate=30/1 ! i.sink_1")
         } else if cfg!(target_os = "linux") {
-            "input-selector name=i ! tee name=t ! queue ! videoconvert ! x264enc tune=zerolatency ! rtph264pay ! multiudpsink name=s t. ! queue ! videoconvert ! jpegenc ! appsink max-buffers=1 caps=image/jpeg name=videosink ximagesrc use-damage=false name=src ! video/x-raw,framerate=30/1 ! videoconvert ! i.sink_0 videotestsrc pattern=white ! video/x-raw,framerate=30/1 ! i.sink_1"
+            format!("input-selector name=i ! tee name=t ! queue ! videoconvert ! x264enc name=enc tune=zerolatency ! {H264_CAPS} ! tee name=h264 ! queue ! rtph264pay name=pay config-interval=-1 pt=96 ! capsfilter name=extmap ! rtpulpfecenc name=fec pt=122 ! rtprtxsend name=rtx ! rtpbin.send_rtp_sink_0 rtpbin name=rtpbin rtp-profile=avpf rtpbin.send_rtp_src_0 ! srtpenc name=srtpenc ! tee name=rtp ! queue ! multiudpsink name=s rtp. ! queue ! appsink name=rtpsink sync=false rtpbin.send_rtcp_src_0 ! multiudpsink name=rtcpsink sync=false async=false funnel name=rtcpsrc ! rtpbin.recv_rtcp_sink_0 t. ! queue ! videoconvert ! jpegenc ! appsink max-buffers=1 caps=image/jpeg name=videosink ximagesrc use-damage=false name=src ! video/x-raw,framerate=30/1 ! videoconvert ! i.sink_0 videotestsrc pattern=white ! video/x-raw,framerate=30/1 ! i.sink_1")
         } else {
-            "input-selector name=i ! tee name=t ! queue ! videoconvert ! x264enc tune=zerolatency ! rtph264pay ! multiudpsink name=s t. ! queue ! videoconvert ! jpegenc ! appsink max-buffers=1 caps=image/jpeg name=videosink avfvideosrc capture-screen=1 capture-screen-cursor=1 name=src ! video/x-raw,framerate=30/1 ! videocrop name=crop ! videoconvert ! i.sink_0 videotestsrc pattern=white ! video/x-raw,framerate=30/1 ! videoconvert ! i.sink_1"
+            format!("input-selector name=i ! tee name=t ! queue ! videoconvert ! x264enc name=enc tune=zerolatency ! {H264_CAPS} ! tee name=h264 ! queue ! rtph264pay name=pay config-interval=-1 pt=96 ! capsfilter name=extmap ! rtpulpfecenc name=fec pt=122 ! rtprtxsend name=rtx ! rtpbin.send_rtp_sink_0 rtpbin name=rtpbin rtp-profile=avpf rtpbin.send_rtp_src_0 ! srtpenc name=srtpenc ! tee name=rtp ! queue ! multiudpsink name=s rtp. ! queue ! appsink name=rtpsink sync=false rtpbin.send_rtcp_src_0 ! multiudpsink name=rtcpsink sync=false async=false funnel name=rtcpsrc ! rtpbin.recv_rtcp_sink_0 t. ! queue ! videoconvert ! jpegenc ! appsink max-buffers=1 caps=image/jpeg name=videosink avfvideosrc capture-screen=1 capture-screen-cursor=1 name=src ! video/x-raw,framerate=30/1 ! videocrop name=crop ! videoconvert ! i.sink_0 videotestsrc pattern=white ! video/x-raw,framerate=30/1 ! videoconvert ! i.sink_1")
         };
 
+        // the outputs below share the encoder with the RTP one
+        if config.rtsp.is_some() {
+            pipeline_string.push_str(" h264. ! queue ! appsink name=rtspsink sync=false");
+        }
+        if let Some(hls_config) = &config.hls {
+            pipeline_string.push_str(&HlsOutput::pipeline_branch(hls_config));
+        }
+        if config.srt.is_some() {
+            // the parameter sets are repeated for the viewers joining mid-stream,
+            // the listener doesn't hold the pipeline back until someone calls
+            pipeline_string.push_str(" h264. ! queue ! h264parse config-interval=-1 ! mpegtsmux alignment=7 ! srtsink name=srtsink sync=false async=false wait-for-connection=false");
+        }
+        if config.rtmp.is_some() {
+            pipeline_string.push_str(" h264. ! queue ! appsink name=rtmpsink sync=false");
+        }
+        if let Some(audio) = &config.audio {
+            pipeline_string.push_str(&audio.pipeline_branch());
+        }
+
         // can't panic after pipeline is created correctly
         let pipeline = gst::parse::launch(&pipeline_string)?
             .dynamic_cast::<gst::Pipeline>()
             .unwrap();
         let multiudpsink = pipeline.by_name("s").unwrap();
+        let rtpsink = pipeline
+            .by_name("rtpsink")
+            .unwrap()
+            .dynamic_cast::<gst_app::AppSink>()
+            .unwrap();
         let videosink = pipeline
             .by_name("videosink")
             .unwrap()
@@ -71,20 +450,263 @@ impl StreamingServer {
         let crop = pipeline.by_name("crop").unwrap();
 
         let selector = pipeline.by_name("i").unwrap();
+        // the receivers measure the latency from the time the frames leave the selector
+        stamp_capture_time(&selector.static_pad("src").unwrap());
+        pipeline.by_name("extmap").unwrap().set_property(
+            "caps",
+            gst::Caps::builder("application/x-rtp")
+                .field("extmap-1", CAPTURE_TIME_EXTENSION)
+                .build(),
+        );
+
+        let srtpenc = pipeline.by_name("srtpenc").unwrap();
+        let srtp_key = config.srtp.then(|| {
+            let mut key = vec![0; SRTP_KEY_LEN];
+            rand::thread_rng().fill_bytes(&mut key);
+            key
+        });
+        match &srtp_key {
+            Some(key) => srtpenc.set_property("key", gst::Buffer::from_slice(key.clone())),
+            None => {
+                for property in ["rtp-cipher", "rtp-auth"] {
+                    srtpenc.set_property_from_str(property, "null");
+                }
+            }
+        }
+        // the RTCP reports go out of rtpbin in clear, only the media is encrypted
+        for property in ["rtcp-cipher", "rtcp-auth"] {
+            srtpenc.set_property_from_str(property, "null");
+        }
+
+        // RTX resends the lost packets with payload type 97 on their own SSRC
+        let rtx_map = gst::Structure::builder("application/x-rtp-pt-map");
+        let rtx_map = if config.retransmission {
+            rtx_map.field("96", 97u32)
+        } else {
+            rtx_map
+        };
+        pipeline
+            .by_name("rtx")
+            .unwrap()
+            .set_property("payload-type-map", rtx_map.build());
+        pipeline
+            .by_name("fec")
+            .unwrap()
+            .set_property("percentage", config.fec_percentage);
+        // the receivers report over the family they connected with
+        listen_udp(&pipeline, "rtcpsrc", config.rtcp_port)?;
+        let audio_sinks = pipeline
+            .by_name("audiosink")
+            .zip(pipeline.by_name("audiortcpsink"));
+        if config.audio.is_some() {
+            listen_udp(&pipeline, "audiortcpsrc", config.rtcp_port.wrapping_add(1))?;
+        }
+
+        let bitrate = BitrateController::new(config.bitrate.clone());
+        let encoder = pipeline.by_name("enc").unwrap();
+        encoder.set_property("bitrate", bitrate.bitrate());
+        let bitrate = Arc::new(Mutex::new(bitrate));
+        let rtpbin = pipeline.by_name("rtpbin").unwrap();
+        let encoded = Arc::new(RateMeter::new());
+        encoded.watch(&encoder.static_pad("src").unwrap());
+
+        let media = MediaSession {
+            srtp_key,
+            multicast: config.multicast.as_ref().map(|multicast| multicast.group),
+            rtcp_port: config.rtcp_port,
+            audio: config.audio.is_some(),
+        };
+
+        let paused = Arc::new(AtomicBool::new(false));
+        let blanked = Arc::new(AtomicBool::new(false));
+
+        let receivers = Receivers {
+            multicast: config.multicast.is_some(),
+            multiudpsink,
+            rtcpsink: pipeline.by_name("rtcpsink").unwrap(),
+            audio_sinks,
+            payloader: pipeline.by_name("pay").unwrap(),
+            pending: HashMap::new(),
+            peers: HashMap::new(),
+            banned: HashSet::new(),
+            reports: HashMap::new(),
+        };
+        if let Some(multicast) = &config.multicast {
+            let audio_sinks = receivers.audio_sinks.iter().flat_map(|(a, b)| [a, b]);
+            for sink in [&receivers.multiudpsink, &receivers.rtcpsink]
+                .into_iter()
+                .chain(audio_sinks)
+            {
+                sink.set_property("ttl-mc", multicast.ttl as i32);
+            }
+            receivers.update_destination("add", multicast.group.ip(), multicast.group.port());
+            println!("Multicasting to {}", multicast.group);
+        }
+        let receivers = Arc::new(Mutex::new(receivers));
+
+        let receivers_clone = receivers.clone();
+        let receivers_clone2 = receivers.clone();
+        let receivers_clone3 = receivers.clone();
+        let paused_clone = paused.clone();
+        let blanked_clone = blanked.clone();
+        let media_clone = media.clone();
+        let require_approval = config.require_approval;
+        let beacon = Beacon {
+            version: PROTOCOL_VERSION,
+            name: config.name.clone(),
+            port: config.port,
+            passphrase_required: config.passphrase.is_some(),
+            tls: config.tls.is_some(),
+        };
 
-        let multiudpsink = Arc::new(multiudpsink);
-        let multiudpsink2 = multiudpsink.clone();
         let connection_server = ConnectionServer::new(
-            move |ip| {
-                multiudpsink.emit_by_name_with_values("add", &[ip.into(), 9001.into()]);
-                println!("Connected: {}", ip);
+            ConnectionServerConfig {
+                port: config.port,
+                secret: config.passphrase,
+                timeout: config.timeout,
+                tls: config.tls,
+                viewer: config.viewer,
+            },
+            move |handle, endpoint, hello| {
+                let addr = handle.peer_addr(endpoint);
+                let mut receivers = receivers_clone.lock().unwrap();
+                if receivers.banned.contains(&addr.ip()) {
+                    println!("Refused banned receiver: {}", addr);
+                    handle.send(
+                        endpoint,
+                        &ControlMessage::Rejected("You are banned by the caster".to_string()),
+                    );
+                    handle.disconnect(endpoint);
+                } else if require_approval {
+                    receivers
+                        .pending
+                        .insert(endpoint, (addr.ip(), hello.clone()));
+                    handle.send(endpoint, &ControlMessage::AwaitingApproval);
+                    println!("Waiting approval: {} ({})", addr, hello.name);
+                } else {
+                    receivers.add(endpoint, addr.ip(), hello);
+                    handle.send(
+                        endpoint,
+                        &stream_info(&paused_clone, &blanked_clone, &media_clone),
+                    );
+                }
+            },
+            move |handle, endpoint, message| match message {
+                ControlMessage::SetMediaTransport(transport) => receivers_clone3
+                    .lock()
+                    .unwrap()
+                    .set_transport(endpoint, transport),
+                ControlMessage::RequestKeyframe => {
+                    receivers_clone3.lock().unwrap().force_keyframe()
+                }
+                ControlMessage::ReceptionReport(report) => {
+                    receivers_clone3.lock().unwrap().report(endpoint, report)
+                }
+                message => println!(
+                    "Message from {}: {:?}",
+                    handle.peer_addr(endpoint).ip(),
+                    message
+                ),
             },
-            move |ip| {
-                multiudpsink2.emit_by_name_with_values("remove", &[ip.into(), 9001.into()]);
-                println!("Disconnected: {}", ip);
+            move |endpoint, _| {
+                receivers_clone2.lock().unwrap().remove(endpoint);
             },
         )?;
 
+        if config.bitrate.adaptive {
+            adapt_bitrate(
+                Arc::downgrade(&receivers),
+                Arc::downgrade(&bitrate),
+                encoder.downgrade(),
+            );
+        }
+
+        let rtsp = match config.rtsp {
+            Some(rtsp_config) => {
+                let receivers = Arc::downgrade(&receivers);
+                let rtsp = Arc::new(RtspOutput::new(&rtsp_config, move || {
+                    if let Some(receivers) = receivers.upgrade() {
+                        receivers.lock().unwrap().force_keyframe();
+                    }
+                })?);
+                let rtsp_clone = Arc::downgrade(&rtsp);
+                forward_frames(&pipeline.by_name("rtspsink").unwrap(), move |buffer| {
+                    if let Some(rtsp) = rtsp_clone.upgrade() {
+                        rtsp.push(buffer);
+                    }
+                });
+                Some(rtsp)
+            }
+            None => None,
+        };
+
+        let hls = match &config.hls {
+            Some(hls_config) => Some(
+                HlsOutput::new(hls_config, &pipeline.by_name("hls").unwrap())
+                    .map_err(StreamingServerError::HlsError)?,
+            ),
+            None => None,
+        };
+
+        let rtmp = match &config.rtmp {
+            Some(rtmp_config) => {
+                let receivers = Arc::downgrade(&receivers);
+                let rtmp = Arc::new(RtmpOutput::new(rtmp_config, move || {
+                    if let Some(receivers) = receivers.upgrade() {
+                        receivers.lock().unwrap().force_keyframe();
+                    }
+                })?);
+                let rtmp_clone = Arc::downgrade(&rtmp);
+                forward_frames(&pipeline.by_name("rtmpsink").unwrap(), move |buffer| {
+                    if let Some(rtmp) = rtmp_clone.upgrade() {
+                        rtmp.push(buffer);
+                    }
+                });
+                Some(rtmp)
+            }
+            None => None,
+        };
+
+        if let Some(srt_config) = &config.srt {
+            let srtsink = pipeline.by_name("srtsink").unwrap();
+            srt_config.apply(&srtsink)?;
+            let receivers = Arc::downgrade(&receivers);
+            srtsink.connect("caller-added", false, move |_| {
+                receivers.upgrade()?.lock().unwrap().force_keyframe();
+                None
+            });
+        }
+
+        // receivers can still type the address when the beacon can't be sent
+        let announcer = config.discovery_port.and_then(|port| {
+            Announcer::new(beacon, port)
+                .map_err(|e| println!("Discovery disabled: {}", e))
+                .ok()
+        });
+
+        // forwards the same packets sent by multiudpsink to the receivers that can't get UDP
+        let handle = connection_server.handle();
+        let receivers_clone = receivers.clone();
+        rtpsink.set_callbacks(
+            gst_app::AppSinkCallbacks::builder()
+                .new_sample(move |appsink| {
+                    let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    let endpoints = receivers_clone.lock().unwrap().websocket_peers();
+                    if endpoints.is_empty() {
+                        return Ok(gst::FlowSuccess::Ok);
+                    }
+                    let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
+                    let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                    let message = ControlMessage::Media(map.as_slice().to_vec());
+                    for endpoint in endpoints {
+                        handle.send(endpoint, &message);
+                    }
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+
+        let viewer_handle = connection_server.handle();
         videosink.set_callbacks(
             gst_app::AppSinkCallbacks::builder()
                 .new_sample(move |appsink| {
@@ -119,6 +741,7 @@ impl StreamingServer {
                         gst::FlowError::Error
                     })?;
 
+                    viewer_handle.publish_frame(samples);
                     image_parser(samples);
 
                     Ok(gst::FlowSuccess::Ok)
@@ -137,16 +760,246 @@ impl StreamingServer {
 
             selector,
 
-            _connection_server: connection_server,
+            receivers,
+
+            paused,
+            blanked,
+
+            media,
+
+            bitrate,
+
+            encoder,
+            rtpbin,
+            encoded,
+
+            connection_server,
+
+            rtsp,
+            hls,
+            srt_url: config.srt.as_ref().map(SrtConfig::url),
+            rtmp,
+
+            _announcer: announcer,
         })
     }
 
+    /// Receivers that connected while `require_approval` is set and are still waiting
+    pub fn pending_peers(&self) -> Vec<PeerRequest> {
+        self.receivers
+            .lock()
+            .unwrap()
+            .pending
+            .iter()
+            .map(|(endpoint, (ip, hello))| PeerRequest {
+                endpoint: *endpoint,
+                ip: *ip,
+                name: hello.name.clone(),
+            })
+            .collect()
+    }
+
+    pub fn allow(&self, request: &PeerRequest) {
+        let mut receivers = self.receivers.lock().unwrap();
+        if let Some((ip, hello)) = receivers.pending.remove(&request.endpoint) {
+            receivers.add(request.endpoint, ip, &hello);
+            self.connection_server.send(
+                request.endpoint,
+                &stream_info(&self.paused, &self.blanked, &self.media),
+            );
+        }
+    }
+
+    pub fn deny(&self, request: &PeerRequest) {
+        let hello = self
+            .receivers
+            .lock()
+            .unwrap()
+            .pending
+            .remove(&request.endpoint);
+        if hello.is_some() {
+            println!("Denied: {} ({})", request.ip, request.name);
+            self.reject(request.endpoint, "The caster denied the request");
+        }
+    }
+
+    /// Receivers currently getting the stream, oldest first
+    pub fn peers(&self) -> Vec<Peer> {
+        let mut peers: Vec<Peer> = self
+            .receivers
+            .lock()
+            .unwrap()
+            .peers
+            .values()
+            .cloned()
+            .collect();
+        peers.sort_by_key(|peer| peer.connected_at);
+        peers
+    }
+
+    pub fn kick(&self, peer: &Peer) {
+        if self.receivers.lock().unwrap().remove(peer.endpoint) {
+            self.reject(peer.endpoint, "You were removed by the caster");
+        }
+    }
+
+    /// Kicks every receiver connected from `ip` and refuses it until `unban`
+    pub fn ban(&self, ip: IpAddr) {
+        let mut receivers = self.receivers.lock().unwrap();
+        receivers.banned.insert(ip);
+        let endpoints: Vec<Endpoint> = receivers
+            .peers
+            .values()
+            .map(|peer| (peer.endpoint, peer.ip))
+            .chain(
+                receivers
+                    .pending
+                    .iter()
+                    .map(|(endpoint, (ip, _))| (*endpoint, *ip)),
+            )
+            .filter(|(_, peer_ip)| *peer_ip == ip)
+            .map(|(endpoint, _)| endpoint)
+            .collect();
+        for endpoint in endpoints {
+            receivers.remove(endpoint);
+            self.reject(endpoint, "You are banned by the caster");
+        }
+    }
+
+    pub fn unban(&self, ip: IpAddr) {
+        self.receivers.lock().unwrap().banned.remove(&ip);
+    }
+
+    /// Where players can watch the stream, when the RTSP output is enabled
+    pub fn rtsp_url(&self) -> Option<String> {
+        self.rtsp.as_ref().map(|rtsp| rtsp.url())
+    }
+
+    /// Page browsers can watch the stream on, when the HLS output is enabled
+    pub fn hls_url(&self) -> Option<String> {
+        self.hls.as_ref().map(|hls| hls.url())
+    }
+
+    /// Where the SRT output can be reached or pushes to, when enabled
+    pub fn srt_url(&self) -> Option<String> {
+        self.srt_url.clone()
+    }
+
+    /// Server the stream is pushed to, without the stream key, when the RTMP output is enabled
+    pub fn rtmp_url(&self) -> Option<String> {
+        self.rtmp.as_ref().map(|rtmp| rtmp.url().to_string())
+    }
+
+    /// Page browsers can follow the preview frames on, when the viewer is enabled
+    pub fn viewer_url(&self) -> Option<String> {
+        self.connection_server.viewer_url()
+    }
+
+    /// Browsers watching through the viewer
+    pub fn viewers(&self) -> usize {
+        self.connection_server.viewers()
+    }
+
+    /// Fingerprint of the TLS certificate, to be checked by the receivers on first use
+    pub fn fingerprint(&self) -> Option<String> {
+        self.connection_server.fingerprint().map(str::to_string)
+    }
+
+    /// Current bitrate of the encoder in kbit/s
+    pub fn bitrate(&self) -> u32 {
+        self.bitrate.lock().unwrap().bitrate()
+    }
+
+    /// Latest changes of the bitrate with their reason, oldest first
+    pub fn bitrate_changes(&self) -> Vec<BitrateChange> {
+        self.bitrate.lock().unwrap().history()
+    }
+
+    /// Statistics of the encoder, of the RTP session and of every receiver
+    pub fn stats(&self) -> ServerStats {
+        let (encoded_fps, bitrate) = self.encoded.rates();
+        let sources = rtp_sources(&self.rtpbin);
+        let packets_sent = sources
+            .iter()
+            .filter(|source| source.get::<bool>("internal").unwrap_or(false))
+            .map(|source| source.get::<u64>("packets-sent").unwrap_or(0))
+            .sum();
+        // the receivers tell the SSRC of their RTCP in their reception reports
+        let round_trips: HashMap<u32, Duration> = sources
+            .iter()
+            .filter(|source| source.get::<bool>("have-rb").unwrap_or(false))
+            .filter_map(|source| {
+                // in units of 1/65536 seconds
+                let round_trip = source.get::<u32>("rb-round-trip").ok()?;
+                Some((
+                    source.get::<u32>("ssrc").ok()?,
+                    Duration::from_secs_f64(round_trip as f64 / 65536.0),
+                ))
+            })
+            .collect();
+        let peers = self
+            .peers()
+            .into_iter()
+            .map(|peer| {
+                let round_trip = match peer.transport {
+                    MediaTransport::Websocket => None,
+                    _ => peer
+                        .reception
+                        .and_then(|report| round_trips.get(&report.ssrc).copied()),
+                };
+                PeerStats {
+                    fraction_lost: peer.reception.map(|report| report.fraction_lost),
+                    jitter_ms: peer.reception.map(|report| report.jitter_ms),
+                    latency: round_trip.zip(peer.reception).map(|(round_trip, report)| {
+                        round_trip / 2 + Duration::from_secs_f32(report.latency_ms / 1000.0)
+                    }),
+                    round_trip,
+                    name: peer.name,
+                    ip: peer.ip,
+                    transport: peer.transport,
+                }
+            })
+            .collect();
+        ServerStats {
+            encoded_fps,
+            bitrate,
+            target_bitrate: self.bitrate(),
+            packets_sent,
+            resolution: resolution(&self.encoder.static_pad("sink").unwrap()),
+            peers,
+        }
+    }
+
+    pub fn banned(&self) -> Vec<IpAddr> {
+        self.receivers
+            .lock()
+            .unwrap()
+            .banned
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    fn reject(&self, endpoint: Endpoint, reason: &str) {
+        self.connection_server
+            .send(endpoint, &ControlMessage::Rejected(reason.to_string()));
+        self.connection_server.disconnect(endpoint);
+    }
+
     pub fn start(&self) -> Result<(), StreamingServerError> {
-        Ok(self.pipeline.set_state(gst::State::Playing).map(|_| ())?)
+        self.pipeline.set_state(gst::State::Playing)?;
+        if self.paused.swap(false, Ordering::Relaxed) {
+            self.connection_server.broadcast(&ControlMessage::Resumed);
+        }
+        Ok(())
     }
 
     pub fn pause(&self) -> Result<(), StreamingServerError> {
-        Ok(self.pipeline.set_state(gst::State::Paused).map(|_| ())?)
+        self.pipeline.set_state(gst::State::Paused)?;
+        if !self.paused.swap(true, Ordering::Relaxed) {
+            self.connection_server.broadcast(&ControlMessage::Paused);
+        }
+        Ok(())
     }
 
     #[cfg(target_os = "linux")]
@@ -183,11 +1036,78 @@ impl StreamingServer {
     pub fn blank_screen(&self) {
         self.selector
             .set_property("active-pad", &self.selector.static_pad("sink_1").unwrap());
+        if !self.blanked.swap(true, Ordering::Relaxed) {
+            self.connection_server.broadcast(&ControlMessage::Blanked);
+        }
     }
 
     pub fn restore_screen(&self) {
         self.selector
             .set_property("active-pad", &self.selector.static_pad("sink_0").unwrap());
+        if self.blanked.swap(false, Ordering::Relaxed) {
+            self.connection_server.broadcast(&ControlMessage::Restored);
+        }
+    }
+}
+
+/// Hands the encoded frames reaching an appsink of the pipeline to an output
+fn forward_frames(appsink: &gst::Element, push: impl Fn(&gst::Buffer) + Send + Sync + 'static) {
+    appsink
+        .clone()
+        .dynamic_cast::<gst_app::AppSink>()
+        .unwrap()
+        .set_callbacks(
+            gst_app::AppSinkCallbacks::builder()
+                .new_sample(move |appsink| {
+                    let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    if let Some(buffer) = sample.buffer_owned() {
+                        push(&buffer);
+                    }
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+}
+
+/// Periodically feeds the receivers' reports to the controller and applies its
+/// decisions to the encoder, until the caster is dropped
+fn adapt_bitrate(
+    receivers: Weak<Mutex<Receivers>>,
+    controller: Weak<Mutex<BitrateController>>,
+    encoder: glib::WeakRef<gst::Element>,
+) {
+    thread::spawn(move || loop {
+        thread::sleep(ADAPTATION_INTERVAL);
+        let (receivers, controller, encoder) =
+            match (receivers.upgrade(), controller.upgrade(), encoder.upgrade()) {
+                (Some(receivers), Some(controller), Some(encoder)) => {
+                    (receivers, controller, encoder)
+                }
+                _ => return,
+            };
+        let reports = receivers.lock().unwrap().take_reports();
+        let mut controller = controller.lock().unwrap();
+        if let Some(change) = controller.update(&reports) {
+            // x264enc reconfigures itself while playing
+            encoder.set_property("bitrate", change.bitrate);
+            println!(
+                "Bitrate set to {} kbit/s: {}",
+                change.bitrate, change.reason
+            );
+        }
+    });
+}
+
+fn stream_info(paused: &AtomicBool, blanked: &AtomicBool, media: &MediaSession) -> ControlMessage {
+    ControlMessage::StreamInfo {
+        codec: "H264".to_string(),
+        framerate: 30,
+        paused: paused.load(Ordering::Relaxed),
+        blanked: blanked.load(Ordering::Relaxed),
+        srtp_key: media.srtp_key.clone(),
+        multicast: media.multicast,
+        rtcp_port: media.rtcp_port,
+        audio: media.audio,
     }
 }
 
@@ -196,3 +1116,100 @@ impl Drop for StreamingServer {
         let _ = self.pipeline.set_state(gst::State::Null);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rtsp_is_refused_when_receivers_are_admitted() {
+        let rtsp = StreamingServerConfig {
+            rtsp: Some(RtspConfig::default()),
+            ..Default::default()
+        };
+        // SRTP alone doesn't admit anyone, RTSP is just unencrypted then
+        assert!(rtsp.srtp && !rtsp.is_protected());
+        assert!(rtsp.check_outputs().is_ok());
+        for config in [
+            StreamingServerConfig {
+                passphrase: Some("secret".to_string()),
+                ..rtsp.clone()
+            },
+            StreamingServerConfig {
+                require_approval: true,
+                ..rtsp.clone()
+            },
+        ] {
+            assert!(matches!(
+                config.check_outputs(),
+                Err(StreamingServerError::UnprotectedOutput("RTSP"))
+            ));
+        }
+    }
+
+    #[test]
+    fn hls_is_refused_when_receivers_are_admitted() {
+        let hls = StreamingServerConfig {
+            hls: Some(HlsConfig::default()),
+            ..Default::default()
+        };
+        assert!(hls.check_outputs().is_ok());
+        for config in [
+            StreamingServerConfig {
+                passphrase: Some("secret".to_string()),
+                ..hls.clone()
+            },
+            StreamingServerConfig {
+                require_approval: true,
+                ..hls.clone()
+            },
+        ] {
+            assert!(matches!(
+                config.check_outputs(),
+                Err(StreamingServerError::UnprotectedOutput("HLS"))
+            ));
+        }
+    }
+
+    #[test]
+    fn srt_needs_a_passphrase_when_receivers_are_admitted() {
+        let srt = StreamingServerConfig {
+            srt: Some(SrtConfig::default()),
+            ..Default::default()
+        };
+        assert!(srt.check_outputs().is_ok());
+        let approval = StreamingServerConfig {
+            require_approval: true,
+            ..srt.clone()
+        };
+        assert!(matches!(
+            approval.check_outputs(),
+            Err(StreamingServerError::UnprotectedOutput(_))
+        ));
+        let keyed = StreamingServerConfig {
+            srt: Some(SrtConfig {
+                passphrase: Some("a long passphrase".to_string()),
+                ..Default::default()
+            }),
+            ..approval
+        };
+        assert!(keyed.check_outputs().is_ok());
+    }
+
+    #[test]
+    fn viewer_is_refused_when_receivers_are_admitted() {
+        let viewer = StreamingServerConfig {
+            viewer: Some(ViewerConfig::default()),
+            ..Default::default()
+        };
+        assert!(viewer.check_outputs().is_ok());
+        let protected = StreamingServerConfig {
+            passphrase: Some("secret".to_string()),
+            ..viewer
+        };
+        assert!(matches!(
+            protected.check_outputs(),
+            Err(StreamingServerError::UnprotectedOutput(_))
+        ));
+    }
+}
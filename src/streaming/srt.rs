@@ -0,0 +1,111 @@
+//! SRT transport, next to the RTP one: the caster can push the stream to an
+//! SRT ingest point and a receiver can pull it from one, or from a caster
+//! listening for SRT callers. The H.264 is carried in MPEG-TS, like most SRT
+//! servers expect, and SRT resends the lost packets within the latency.
+//!
+//! SRT only checks its passphrase, the callers don't go through the caster's
+//! approval, kick nor ban. So when the receivers are admitted with a passphrase
+//! or approval, the caster refuses to send SRT without a passphrase.
+//!
+//! A loopback test between the two sides is a caster with the default
+//! (listener) configuration and a receiver calling `127.0.0.1`:
+//!
+//! ```text
+//! gst-launch-1.0 srtsrc uri=srt://127.0.0.1:9020 ! tsdemux ! h264parse ! avdec_h264 ! videoconvert ! autovideosink
+//! ```
+
+use std::time::Duration;
+
+use gst::prelude::*;
+use gstreamer as gst;
+
+use gst::glib;
+
+use crate::connection::{host_port, local_ip};
+
+pub const DEFAULT_SRT_PORT: u16 = 9020;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SrtMode {
+    /// Waits for the other side to connect
+    Listener,
+    /// Connects to the other side, which has to be listening
+    Caller,
+}
+
+#[derive(Clone, Debug)]
+pub struct SrtConfig {
+    pub mode: SrtMode,
+    /// Host to connect to as caller, local address to listen on as listener
+    /// (empty for all the interfaces)
+    pub host: String,
+    pub port: u16,
+    /// When set the stream is encrypted, both sides need the same one (10 to 79 characters)
+    pub passphrase: Option<String>,
+    /// How long lost packets can be waited for, the larger of the two sides' values is used
+    pub latency: Duration,
+}
+
+impl Default for SrtConfig {
+    fn default() -> Self {
+        Self {
+            mode: SrtMode::Listener,
+            host: String::new(),
+            port: DEFAULT_SRT_PORT,
+            passphrase: None,
+            latency: Duration::from_millis(120),
+        }
+    }
+}
+
+impl SrtConfig {
+    /// A caller of `host` with the other settings left to their defaults
+    pub fn caller(host: impl Into<String>) -> Self {
+        Self {
+            mode: SrtMode::Caller,
+            host: host.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn uri(&self) -> String {
+        let mode = match self.mode {
+            SrtMode::Listener => "listener",
+            SrtMode::Caller => "caller",
+        };
+        format!(
+            "srt://{}?mode={}&latency={}",
+            host_port(&self.host, self.port),
+            mode,
+            self.latency.as_millis()
+        )
+    }
+
+    /// What the other side connects to, or what is connected to
+    pub fn url(&self) -> String {
+        match self.mode {
+            // the callers need an address rather than "all the interfaces"
+            SrtMode::Listener if self.host.is_empty() => {
+                let host = local_ip().map_or_else(|| "localhost".to_string(), |ip| ip.to_string());
+                format!("srt://{}", host_port(&host, self.port))
+            }
+            _ => format!("srt://{}", host_port(&self.host, self.port)),
+        }
+    }
+
+    /// Applies the configuration to an srtsink or srtsrc
+    pub(crate) fn apply(&self, element: &gst::Element) -> Result<(), glib::BoolError> {
+        element.set_property("uri", self.uri());
+        // set apart, the URI would need it escaped
+        if let Some(passphrase) = &self.passphrase {
+            // SRT would only refuse it when connecting
+            if !(10..=79).contains(&passphrase.len()) {
+                return Err(glib::bool_error!(
+                    "SRT passphrases must have 10 to 79 characters"
+                ));
+            }
+            element.set_property("passphrase", passphrase);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+//! Statistics of the streams, gathered from pad probes and the RTP session.
+
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use gst::glib;
+use gst::prelude::*;
+use gstreamer as gst;
+
+use crate::connection::message::MediaTransport;
+
+/// Rates are averaged over this window
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Default)]
+pub struct ServerStats {
+    /// Frames per second out of the encoder
+    pub encoded_fps: f32,
+    /// Measured output of the encoder in kbit/s
+    pub bitrate: u32,
+    /// Bitrate the encoder is asked for in kbit/s, see `StreamingServer::bitrate`
+    pub target_bitrate: u32,
+    /// RTP packets sent since the start, retransmissions and FEC included
+    pub packets_sent: u64,
+    /// Size of the encoded frames, None until the first one
+    pub resolution: Option<(u32, u32)>,
+    pub peers: Vec<PeerStats>,
+}
+
+/// What the caster knows about the reception of a receiver
+#[derive(Clone, Debug)]
+pub struct PeerStats {
+    pub name: String,
+    pub ip: IpAddr,
+    pub transport: MediaTransport,
+    /// From the receiver's reception reports, 0.0 to 1.0
+    pub fraction_lost: Option<f32>,
+    pub jitter_ms: Option<f32>,
+    /// Round trip time computed from the RTCP receiver reports of the peer's address
+    pub round_trip: Option<Duration>,
+    /// Half the round trip plus the latency of the receiver's pipeline
+    pub latency: Option<Duration>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ClientStats {
+    /// Frames per second out of the decoder
+    pub decoded_fps: f32,
+    /// Measured media received in kbit/s, retransmissions and FEC included
+    pub bitrate: u32,
+    pub packets_received: u64,
+    pub packets_lost: u64,
+    /// Lost packets over expected ones since the start, 0.0 to 1.0
+    pub fraction_lost: f32,
+    pub jitter_ms: f32,
+    /// Latency of the receiving pipeline (jitter buffer and decoding), the
+    /// network adds the one way delay to it
+    pub latency: Option<Duration>,
+    /// Measured from the capture to the decoding of the last frame,
+    /// see `StreamingClient::latency`
+    pub glass_to_glass: Option<Duration>,
+    /// Size of the decoded frames, None until the first one
+    pub resolution: Option<(u32, u32)>,
+}
+
+/// Counts the buffers and bytes going through a pad
+pub(crate) struct RateMeter {
+    state: Mutex<RateState>,
+}
+
+struct RateState {
+    window_start: Instant,
+    buffers: u32,
+    bytes: u64,
+    /// Rates of the last complete window
+    per_second: f32,
+    kbit_per_second: u32,
+}
+
+impl RateMeter {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(RateState {
+                window_start: Instant::now(),
+                buffers: 0,
+                bytes: 0,
+                per_second: 0.0,
+                kbit_per_second: 0,
+            }),
+        }
+    }
+
+    pub fn add(&self, bytes: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.buffers += 1;
+        state.bytes += bytes as u64;
+        let elapsed = state.window_start.elapsed();
+        if elapsed >= RATE_WINDOW {
+            state.per_second = state.buffers as f32 / elapsed.as_secs_f32();
+            state.kbit_per_second = (state.bytes * 8 / elapsed.as_millis().max(1) as u64) as u32;
+            state.window_start = Instant::now();
+            state.buffers = 0;
+            state.bytes = 0;
+        }
+    }
+
+    /// Buffers per second and kbit/s, zero when nothing went through lately
+    pub fn rates(&self) -> (f32, u32) {
+        let state = self.state.lock().unwrap();
+        if state.window_start.elapsed() > RATE_WINDOW * 2 {
+            (0.0, 0)
+        } else {
+            (state.per_second, state.kbit_per_second)
+        }
+    }
+
+    /// Feeds the meter with the buffers going through the pad
+    pub fn watch(self: &Arc<Self>, pad: &gst::Pad) {
+        let meter = Arc::downgrade(self);
+        pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            match (meter.upgrade(), info.buffer()) {
+                (Some(meter), Some(buffer)) => meter.add(buffer.size()),
+                (None, _) => return gst::PadProbeReturn::Remove,
+                _ => {}
+            }
+            gst::PadProbeReturn::Ok
+        });
+    }
+}
+
+/// Width and height of the video negotiated on the pad
+pub(crate) fn resolution(pad: &gst::Pad) -> Option<(u32, u32)> {
+    let caps = pad.current_caps()?;
+    let structure = caps.structure(0)?;
+    let width = structure.get::<i32>("width").ok()?;
+    let height = structure.get::<i32>("height").ok()?;
+    Some((width as u32, height as u32))
+}
+
+/// Statistics of every source (SSRC) of the first rtpbin session
+pub(crate) fn rtp_sources(rtpbin: &gst::Element) -> Vec<gst::Structure> {
+    let session = rtpbin.emit_by_name::<glib::Object>("get-internal-session", &[&0u32]);
+    let stats = session.property::<gst::Structure>("stats");
+    match stats.get::<glib::ValueArray>("source-stats") {
+        Ok(sources) => sources
+            .iter()
+            .filter_map(|source| source.get::<gst::Structure>().ok())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Latency of a live pipeline, the time a buffer spends in it before being rendered
+pub(crate) fn latency(pipeline: &gst::Pipeline) -> Option<Duration> {
+    let mut query = gst::query::Latency::new();
+    if !pipeline.query(&mut query) {
+        return None;
+    }
+    let (live, min, _) = query.result();
+    live.then(|| Duration::from_nanos(min.nseconds()))
+}
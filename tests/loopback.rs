@@ -0,0 +1,337 @@
+//! Caster, receivers and the other outputs talking over loopback, with
+//! `netsim` dropping part of the receiver's packets in the loss tests. They need GStreamer with the good, bad and ugly
+//! plugins and a screen to capture, so they only run when asked for:
+//!
+//! ```text
+//! cargo test --test loopback -- --ignored --test-threads=1
+//! ```
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app as gst_app;
+use rust_streamer::streaming::client::{StreamingClient, StreamingClientConfig};
+use rust_streamer::streaming::hls::HlsConfig;
+use rust_streamer::streaming::relay::RelayConfig;
+use rust_streamer::streaming::rtsp::RtspConfig;
+use rust_streamer::streaming::server::{StreamingServer, StreamingServerConfig};
+use rust_streamer::streaming::srt::SrtConfig;
+
+/// Away from the default ports, a caster may be running on the host
+const SIGNALING_PORT: u16 = 19000;
+const MEDIA_PORT: u16 = 19001;
+const RTCP_PORT: u16 = 19005;
+const RELAY_PORT: u16 = 19010;
+const RELAY_RTCP_PORT: u16 = 19015;
+/// Media port of the relay's receiver, the relaying receiver has `MEDIA_PORT`
+const RELAYED_MEDIA_PORT: u16 = 19101;
+const RTSP_PORT: u16 = 18554;
+const HLS_PORT: u16 = 18080;
+const SRT_PORT: u16 = 19020;
+
+/// Caster on the test ports, without discovery
+fn caster_config() -> StreamingServerConfig {
+    StreamingServerConfig {
+        port: SIGNALING_PORT,
+        rtcp_port: RTCP_PORT,
+        discovery_port: None,
+        ..Default::default()
+    }
+}
+
+/// Receiver of the test caster, video only
+fn receiver_config() -> StreamingClientConfig {
+    StreamingClientConfig {
+        port: SIGNALING_PORT,
+        media_port: MEDIA_PORT,
+        audio: false,
+        ..Default::default()
+    }
+}
+
+fn start_caster(config: StreamingServerConfig) -> StreamingServer {
+    let server = StreamingServer::new(|_| {}, config).unwrap();
+    server.start().unwrap();
+    server
+}
+
+/// Starts a receiver of `host`, returns it with the count of the frames it decoded
+fn start_receiver(
+    host: &str,
+    config: StreamingClientConfig,
+) -> (StreamingClient, Arc<AtomicUsize>) {
+    let frames = Arc::new(AtomicUsize::new(0));
+    let frames_clone = frames.clone();
+    let client = StreamingClient::new(
+        host,
+        move |_| {
+            frames_clone.fetch_add(1, Ordering::Relaxed);
+        },
+        false,
+        config,
+    )
+    .unwrap();
+    client.start().unwrap();
+    (client, frames)
+}
+
+/// Starts a caster and a receiver dropping `loss` of the packets, returns the
+/// frames decoded and the loss the receiver reported after recovery, every second
+fn stream_with_loss(
+    loss: f32,
+    retransmission: bool,
+    seconds: u64,
+) -> (StreamingServer, StreamingClient, usize, Vec<f32>) {
+    let server = start_caster(StreamingServerConfig {
+        retransmission,
+        ..caster_config()
+    });
+    let (client, frames) = start_receiver(
+        "127.0.0.1",
+        StreamingClientConfig {
+            simulated_loss: loss,
+            retransmission,
+            // the losses must be recovered over UDP, not avoided through the websocket
+            websocket_fallback: None,
+            ..receiver_config()
+        },
+    );
+
+    // the first reports cover the start, before the first keyframe
+    thread::sleep(Duration::from_secs(3));
+    let mut reported = Vec::new();
+    for _ in 0..seconds {
+        thread::sleep(Duration::from_secs(1));
+        let stats = server.stats();
+        if let Some(lost) = stats.peers.first().and_then(|peer| peer.fraction_lost) {
+            reported.push(lost);
+        }
+    }
+    let frames = frames.load(Ordering::Relaxed);
+    (server, client, frames, reported)
+}
+
+/// Runs a player pipeline ending with `appsink name=sink` for `seconds`,
+/// returns the buffers that reached the sink
+fn play(description: &str, seconds: u64) -> usize {
+    gst::init().unwrap();
+    let pipeline = gst::parse::launch(description)
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+    let sink = pipeline
+        .by_name("sink")
+        .unwrap()
+        .downcast::<gst_app::AppSink>()
+        .unwrap();
+    pipeline.set_state(gst::State::Playing).unwrap();
+    let started = Instant::now();
+    let mut buffers = 0;
+    while started.elapsed() < Duration::from_secs(seconds) {
+        if sink
+            .try_pull_sample(gst::ClockTime::from_mseconds(100))
+            .is_some()
+        {
+            buffers += 1;
+        }
+    }
+    pipeline.set_state(gst::State::Null).unwrap();
+    buffers
+}
+
+/// GETs `path` from the HTTP server on loopback, returns the status line and the body
+fn http_get(port: u16, path: &str) -> (String, Vec<u8>) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n", path).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let head_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .unwrap();
+    let head = String::from_utf8_lossy(&response[..head_end]);
+    let status = head.lines().next().unwrap_or_default().to_string();
+    (status, response[head_end + 4..].to_vec())
+}
+
+fn average(values: &[f32]) -> f32 {
+    values.iter().sum::<f32>() / values.len().max(1) as f32
+}
+
+#[test]
+#[ignore]
+fn retransmission_recovers_the_losses() {
+    let (_server, client, frames, reported) = stream_with_loss(0.05, true, 10);
+    assert!(frames > 100, "only {} frames decoded", frames);
+    assert!(!reported.is_empty(), "no reception report");
+    // the network lost about 5%, what is left after RTX is what the receiver reports
+    assert!(client.stats().packets_lost > 0, "netsim dropped nothing");
+    assert!(average(&reported) < 0.01, "unrecovered loss {:?}", reported);
+}
+
+#[test]
+#[ignore]
+fn losses_show_without_retransmission() {
+    let (_server, _client, _, reported) = stream_with_loss(0.05, false, 10);
+    assert!(
+        average(&reported) > 0.02,
+        "losses not reported {:?}",
+        reported
+    );
+}
+
+#[test]
+#[ignore]
+fn relay_forwards_the_stream() {
+    let _server = start_caster(caster_config());
+    let (relaying, _) = start_receiver(
+        "127.0.0.1",
+        StreamingClientConfig {
+            relay: Some(RelayConfig {
+                port: RELAY_PORT,
+                rtcp_port: RELAY_RTCP_PORT,
+                ..Default::default()
+            }),
+            ..receiver_config()
+        },
+    );
+    // the relay has nothing to forward before the caster's first keyframe
+    thread::sleep(Duration::from_secs(3));
+    let (_relayed, frames) = start_receiver(
+        "127.0.0.1",
+        StreamingClientConfig {
+            port: RELAY_PORT,
+            media_port: RELAYED_MEDIA_PORT,
+            ..receiver_config()
+        },
+    );
+    thread::sleep(Duration::from_secs(5));
+    assert_eq!(relaying.relay_receivers(), Some(1));
+    let frames = frames.load(Ordering::Relaxed);
+    assert!(frames > 50, "only {} frames relayed", frames);
+}
+
+#[test]
+#[ignore]
+fn relay_stops_when_the_caster_asks_for_approval() {
+    let server = start_caster(StreamingServerConfig {
+        require_approval: true,
+        ..caster_config()
+    });
+    let (relaying, _) = start_receiver(
+        "127.0.0.1",
+        StreamingClientConfig {
+            relay: Some(RelayConfig {
+                port: RELAY_PORT,
+                rtcp_port: RELAY_RTCP_PORT,
+                ..Default::default()
+            }),
+            ..receiver_config()
+        },
+    );
+    thread::sleep(Duration::from_secs(2));
+    assert!(!server.pending_peers().is_empty(), "no receiver waiting");
+    assert!(relaying.is_relay_refused());
+    assert_eq!(relaying.relay_receivers(), None);
+}
+
+#[test]
+#[ignore]
+fn rtsp_serves_the_stream() {
+    let _server = start_caster(StreamingServerConfig {
+        rtsp: Some(RtspConfig {
+            port: RTSP_PORT,
+            ..Default::default()
+        }),
+        ..caster_config()
+    });
+    let frames = play(
+        &format!(
+            "rtspsrc location=rtsp://127.0.0.1:{}/screen latency=200 ! rtph264depay ! h264parse ! avdec_h264 ! appsink name=sink sync=false",
+            RTSP_PORT
+        ),
+        8,
+    );
+    assert!(frames > 50, "only {} frames played", frames);
+}
+
+#[test]
+#[ignore]
+fn hls_serves_the_playlist_and_the_segments() {
+    let directory = std::env::temp_dir().join("rust-streamer-hls-test");
+    let _server = start_caster(StreamingServerConfig {
+        hls: Some(HlsConfig {
+            directory,
+            port: HLS_PORT,
+            target_duration: 1,
+            ..Default::default()
+        }),
+        ..caster_config()
+    });
+    // a few segments have to be written
+    thread::sleep(Duration::from_secs(5));
+
+    let (status, playlist) = http_get(HLS_PORT, "/playlist.m3u8");
+    assert!(status.contains("200"), "{}", status);
+    let playlist = String::from_utf8(playlist).unwrap();
+    assert!(playlist.starts_with("#EXTM3U"), "{}", playlist);
+    let segment = playlist
+        .lines()
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .expect("no segment listed");
+
+    let (status, body) = http_get(HLS_PORT, &format!("/{}", segment));
+    assert!(status.contains("200"), "{}", status);
+    // MPEG-TS packets start with the sync byte
+    assert_eq!(body.first(), Some(&0x47));
+
+    let (status, _) = http_get(HLS_PORT, "/../Cargo.toml");
+    assert!(status.contains("404"), "{}", status);
+}
+
+/// Starts a caster admitting its receivers with a passphrase and listening for
+/// SRT callers, returns the frames decoded by a caller using `srt_passphrase`
+fn stream_over_srt(srt_passphrase: &str) -> usize {
+    let _server = start_caster(StreamingServerConfig {
+        passphrase: Some("signaling passphrase".to_string()),
+        srt: Some(SrtConfig {
+            port: SRT_PORT,
+            passphrase: Some("srt passphrase".to_string()),
+            ..Default::default()
+        }),
+        ..caster_config()
+    });
+    let (_client, frames) = start_receiver(
+        "127.0.0.1",
+        StreamingClientConfig {
+            srt: Some(SrtConfig {
+                port: SRT_PORT,
+                passphrase: Some(srt_passphrase.to_string()),
+                ..SrtConfig::caller("127.0.0.1")
+            }),
+            ..receiver_config()
+        },
+    );
+    thread::sleep(Duration::from_secs(6));
+    frames.load(Ordering::Relaxed)
+}
+
+#[test]
+#[ignore]
+fn srt_carries_the_protected_stream_with_its_passphrase() {
+    let frames = stream_over_srt("srt passphrase");
+    assert!(frames > 50, "only {} frames decoded", frames);
+}
+
+#[test]
+#[ignore]
+fn srt_refuses_a_wrong_passphrase() {
+    let frames = stream_over_srt("wrong passphrase");
+    assert_eq!(frames, 0);
+}